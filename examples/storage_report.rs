@@ -0,0 +1,81 @@
+//! Compares the memory use and query speed of the block formats (including
+//! the struct-of-arrays layout against the plain array of structs) and block
+//! sizes on `Trace::demo_trace` data. Run with
+//! `cargo run --release --example storage_report`.
+
+use fastrand::Rng;
+use gigatrace::iforest::IForestIndex;
+use gigatrace::index::{LongestEvent, TrackIndex};
+use gigatrace::trace::{BlockFormat, BlockPool, TraceEvent, Track};
+use gigatrace::{aggregate_by_steps, Trace};
+use std::time::Instant;
+
+fn formats(tracks: usize, events_per_track: usize) {
+    for &format in &[BlockFormat::Raw, BlockFormat::Soa, BlockFormat::Compressed] {
+        let trace = Trace::demo_trace_in(BlockPool::with_format(format), tracks, events_per_track);
+        let bytes_per_event = trace.pool.memory_usage() as f64 / (tracks * events_per_track) as f64;
+
+        let start = Instant::now();
+        for track in &trace.tracks {
+            IForestIndex::<LongestEvent>::build(&track.track, &trace.pool);
+        }
+        let build_time = start.elapsed();
+
+        let bounds = trace.time_bounds().unwrap();
+        let start = Instant::now();
+        for track in &trace.tracks {
+            // Zoomed all the way out, then a fine but still indexed query
+            for &buckets in &[2000, 200_000] {
+                let step = (bounds.end - bounds.start) / buckets;
+                aggregate_by_steps(&trace.pool, &track.track.block_locs, &track.zoom_index, bounds.clone(), step);
+            }
+        }
+        let query_time = start.elapsed();
+
+        let start = Instant::now();
+        let total_dur: u64 = trace.tracks.iter()
+            .flat_map(|t| t.track.events(&trace.pool))
+            .map(|ev| ev.dur.unpack())
+            .sum();
+        let scan_time = start.elapsed();
+
+        println!(
+            "{:?}: {:.2} bytes/event, index build {:?}, queries {:?}, full scan {:?} (total dur {})",
+            format, bytes_per_event, build_time, query_time, scan_time, total_dur,
+        );
+    }
+}
+
+fn block_size<const N: usize>(tracks: usize, events_per_track: usize) {
+    let mut pool = BlockPool::<TraceEvent, N>::default();
+    let rng = Rng::with_seed(1);
+    let tracks = (0..tracks).map(|_| {
+        let mut track = Track::default();
+        track.add_dummy_events(&mut pool, &rng, events_per_track);
+        let index = IForestIndex::<LongestEvent, TraceEvent, N>::build(&track, &pool);
+        (track, index)
+    }).collect::<Vec<_>>();
+    let index_bytes: usize = tracks.iter().map(|(_, index)| index.memory_usage()).sum();
+
+    let end = tracks.iter().filter_map(|(t, _)| t.end_time(&pool)).max().unwrap();
+    let start = Instant::now();
+    for (track, index) in &tracks {
+        for &buckets in &[2000, 200_000] {
+            aggregate_by_steps(&pool, &track.block_locs, index, 0..end, end / buckets);
+        }
+    }
+    let query_time = start.elapsed();
+
+    println!(
+        "{}-event blocks: pool {} MB, index {} MB, queries {:?}",
+        N, pool.memory_usage() >> 20, index_bytes >> 20, query_time,
+    );
+}
+
+fn main() {
+    let tracks = 8;
+    let events_per_track = 1_000_000;
+    formats(tracks, events_per_track);
+    block_size::<16>(tracks, events_per_track);
+    block_size::<64>(tracks, events_per_track);
+}
@@ -0,0 +1,150 @@
+use crate::trace::{BlockPool, Ns, TraceEvent, Track};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// A slice along with the part of its duration not covered by its direct
+/// children.
+#[derive(Copy, Clone)]
+pub struct SelfTime {
+    pub event: TraceEvent,
+    pub self_time: Ns,
+    /// How many enclosing slices starting inside the queried range this one
+    /// is nested in.
+    pub depth: usize,
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct KindTime {
+    pub kind: u16,
+    pub count: usize,
+    pub total_time: Ns,
+    pub self_time: Ns,
+}
+
+struct OpenSlice {
+    ev: TraceEvent,
+    end: Ns,
+    child_time: Ns,
+    in_range: bool,
+}
+
+/// Walks the slices of `track` which start inside `range`, reconstructing the
+/// nesting from timestamps: a slice is a child of the innermost open slice
+/// that hasn't ended by the time it starts.
+///
+/// `visit` is called once per slice in `range` when it's closed, with the
+/// stack of slices enclosing it, so results come out in end order. Slices
+/// starting after `range.end` are still scanned while a slice from the range
+/// is open, so that they count as children, but aren't visited.
+pub(crate) fn walk_nested<const N: usize>(
+    track: &Track<TraceEvent, N>,
+    pool: &BlockPool<TraceEvent, N>,
+    range: Range<Ns>,
+    mut visit: impl FnMut(&[TraceEvent], &TraceEvent, Ns),
+) {
+    fn close_until(
+        stack: &mut Vec<OpenSlice>,
+        parents: &mut Vec<TraceEvent>,
+        ts: Ns,
+        visit: &mut impl FnMut(&[TraceEvent], &TraceEvent, Ns),
+    ) {
+        while stack.last().is_some_and(|s| s.end <= ts) {
+            let s = stack.pop().unwrap();
+            parents.pop();
+            if s.in_range {
+                visit(parents, &s.ev, s.ev.dur.unpack() - s.child_time);
+            }
+        }
+    }
+
+    let mut stack: Vec<OpenSlice> = vec![];
+    let mut parents: Vec<TraceEvent> = vec![];
+    for ev in track.events_from(pool, range.start) {
+        let ts = ev.ts.unpack();
+        close_until(&mut stack, &mut parents, ts, &mut visit);
+        if ts >= range.end && stack.is_empty() {
+            break;
+        }
+        let end = ts + ev.dur.unpack();
+        if let Some(parent) = stack.last_mut() {
+            // Children poking out past their parent only count up to its end
+            parent.child_time += end.min(parent.end) - ts;
+        }
+        stack.push(OpenSlice { ev, end, child_time: 0, in_range: ts < range.end });
+        parents.push(ev);
+    }
+    close_until(&mut stack, &mut parents, Ns::MAX, &mut visit);
+}
+
+/// Self time of every slice starting inside `range`, in start order.
+pub fn self_times<const N: usize>(track: &Track<TraceEvent, N>, pool: &BlockPool<TraceEvent, N>, range: Range<Ns>) -> Vec<SelfTime> {
+    let mut out = vec![];
+    walk_nested(track, pool, range, |parents, ev, self_time| {
+        out.push(SelfTime { event: *ev, self_time, depth: parents.len() });
+    });
+    // Slices are visited when they end, parents after their children
+    out.sort_by_key(|s| (s.event.ts.unpack(), s.depth));
+    out
+}
+
+/// Sums the total and self time of slices starting inside `range` per kind,
+/// sorted by descending self time so the kinds where time is actually spent
+/// come first.
+pub fn self_time_by_kind<const N: usize>(track: &Track<TraceEvent, N>, pool: &BlockPool<TraceEvent, N>, range: Range<Ns>) -> Vec<KindTime> {
+    let mut by_kind: HashMap<u16, KindTime> = HashMap::new();
+    walk_nested(track, pool, range, |_, ev, self_time| {
+        let k = by_kind.entry(ev.kind).or_insert_with(|| KindTime { kind: ev.kind, ..Default::default() });
+        k.count += 1;
+        k.total_time += ev.dur.unpack();
+        k.self_time += self_time;
+    });
+    let mut out = by_kind.into_values().collect::<Vec<_>>();
+    out.sort_by(|a, b| b.self_time.cmp(&a.self_time).then(a.kind.cmp(&b.kind)));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::PackedNs;
+
+    fn nested_track(pool: &mut BlockPool) -> Track {
+        // 1: [0, 100)
+        //   2: [10, 30)
+        //     3: [12, 20)
+        //   2: [40, 90)
+        // 1: [100, 150)
+        //   3: [140, 160) pokes out of its parent
+        let mut track = Track::new();
+        for &(kind, ts, dur) in &[(1, 0, 100), (2, 10, 20), (3, 12, 8), (2, 40, 50), (1, 100, 50), (3, 140, 20)] {
+            track.push(pool, TraceEvent { kind, ts: PackedNs::new(ts), dur: PackedNs::new(dur) });
+        }
+        track
+    }
+
+    #[test]
+    fn self_times_nested() {
+        let mut pool = BlockPool::new();
+        let track = nested_track(&mut pool);
+        let res = self_times(&track, &pool, 0..1000);
+        let got = res.iter().map(|s| (s.event.kind, s.self_time, s.depth)).collect::<Vec<_>>();
+        assert_eq!(got, vec![(1, 30, 0), (2, 12, 1), (3, 8, 2), (2, 50, 1), (1, 40, 0), (3, 20, 1)]);
+
+        // A range starting inside a parent treats its children as top level
+        let res = self_times(&track, &pool, 5..50);
+        let got = res.iter().map(|s| (s.event.kind, s.self_time, s.depth)).collect::<Vec<_>>();
+        assert_eq!(got, vec![(2, 12, 0), (3, 8, 1), (2, 50, 0)]);
+    }
+
+    #[test]
+    fn self_time_kinds() {
+        let mut pool = BlockPool::new();
+        let track = nested_track(&mut pool);
+        let res = self_time_by_kind(&track, &pool, 0..1000);
+        assert_eq!(res, vec![
+            KindTime { kind: 1, count: 2, total_time: 150, self_time: 70 },
+            KindTime { kind: 2, count: 2, total_time: 70, self_time: 62 },
+            KindTime { kind: 3, count: 2, total_time: 28, self_time: 28 },
+        ]);
+    }
+}
@@ -0,0 +1,45 @@
+use crate::json::Json;
+use std::collections::BTreeMap;
+
+/// Extra details of the events on a track, like the `args` of Chrome trace
+/// events, by the index of the event in the track. They're kept apart from
+/// the event blocks, which stay small and fixed size, since most events
+/// don't have any.
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct EventArgs {
+    by_event: BTreeMap<usize, Vec<(String, Json)>>,
+}
+
+impl EventArgs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the args of the `event`th event, replacing any it had.
+    pub fn insert(&mut self, event: usize, args: Vec<(String, Json)>) {
+        if args.is_empty() {
+            self.by_event.remove(&event);
+        } else {
+            self.by_event.insert(event, args);
+        }
+    }
+
+    /// The args of the `event`th event, empty if it has none.
+    pub fn get(&self, event: usize) -> &[(String, Json)] {
+        self.by_event.get(&event).map_or(&[], |args| &args[..])
+    }
+
+    /// Events with args, in track order.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &[(String, Json)])> + '_ {
+        self.by_event.iter().map(|(&i, args)| (i, &args[..]))
+    }
+
+    /// The number of events with args.
+    pub fn len(&self) -> usize {
+        self.by_event.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_event.is_empty()
+    }
+}
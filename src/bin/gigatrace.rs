@@ -0,0 +1,326 @@
+//! Command line access to traces without the UI, for scripts and for
+//! checking what an importer made of a file.
+
+use gigatrace::analysis::{self_time_by_kind, KindTime};
+use gigatrace::chrome::{import_chrome_json, write_chrome_json};
+use gigatrace::index::LongestEvent;
+use gigatrace::json::Json;
+use gigatrace::render::Snapshot;
+use gigatrace::trace::Ns;
+use gigatrace::view::format_time;
+use gigatrace::{aggregate_by_steps, Trace};
+use std::collections::HashMap;
+use std::fs;
+use std::mem;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::process;
+
+const USAGE: &str = "usage: gigatrace <command> [--json] ...
+
+commands:
+  info <file>                       tracks, event counts, time bounds and memory use
+  convert <in> <out> [--to FORMAT]  FORMAT is chrome or native, by default from the
+                                    extension of <out> (.json is chrome)
+  stats <file> [--range A..B]       count and total and self time of each kind
+  query <file> --track N [--range A..B] [--buckets N | --step T]
+        [--metric count|busy|longest]
+                                    aggregate a track over buckets of time
+  render <file> <out> [--range A..B] [--size WxH]
+                                    draw the tracks to an .svg, .png or .ppm
+
+Files are Chrome trace JSON or gigatrace's native format. Times are relative
+to the start of the trace, in ns or with a unit like 1.5ms.";
+
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+    json: bool,
+}
+
+impl Args {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Args, String> {
+        let mut out = Args { positional: vec![], options: HashMap::new(), json: false };
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            if arg == "--json" {
+                out.json = true;
+            } else if let Some(name) = arg.strip_prefix("--") {
+                let value = args.next().ok_or_else(|| format!("missing value for --{}", name))?;
+                out.options.insert(name.to_owned(), value);
+            } else {
+                out.positional.push(arg);
+            }
+        }
+        Ok(out)
+    }
+
+    fn file(&self, i: usize) -> Result<&str, String> {
+        self.positional.get(i).map(|s| s.as_str()).ok_or_else(|| USAGE.to_owned())
+    }
+
+    fn option<T>(&self, name: &str, parse: impl Fn(&str) -> Option<T>) -> Result<Option<T>, String> {
+        match self.options.get(name) {
+            Some(value) => parse(value).map(Some).ok_or_else(|| format!("bad value for --{}: {}", name, value)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn parse_time(s: &str) -> Option<Ns> {
+    let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let scale = match unit {
+        "" | "ns" => 1.0,
+        "us" => 1e3,
+        "ms" => 1e6,
+        "s" => 1e9,
+        _ => return None,
+    };
+    num.parse::<f64>().ok().map(|x| (x * scale).round() as Ns)
+}
+
+fn parse_range(s: &str) -> Option<Range<Ns>> {
+    let mut parts = s.splitn(2, "..");
+    let start = parse_time(parts.next()?)?;
+    let end = parse_time(parts.next()?)?;
+    if start < end { Some(start..end) } else { None }
+}
+
+fn format_bytes(n: usize) -> String {
+    if n < 1 << 20 {
+        format!("{:.1} KB", n as f64 / 1024.0)
+    } else {
+        format!("{:.1} MB", n as f64 / (1 << 20) as f64)
+    }
+}
+
+fn load(path: &str) -> Result<Trace, String> {
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let trace = if Trace::is_native(&data) {
+        Trace::read_native(&data)
+    } else {
+        let text = String::from_utf8(data).map_err(|_| format!("{}: not a gigatrace file or UTF-8 JSON", path))?;
+        import_chrome_json(&text)
+    };
+    trace.map_err(|e| format!("{}: {}", path, e))
+}
+
+fn info(args: &Args) -> Result<(), String> {
+    let trace = load(args.file(1)?)?;
+    let bounds = trace.time_bounds().unwrap_or(0..0);
+    let tracks = trace.tracks.iter().map(|t| {
+        let events = t.event_count();
+        (&t.meta, events, t.track.start_time(&trace.pool), t.track.after_last_time(&trace.pool))
+    });
+    let instant_tracks = trace.instant_tracks.iter().map(|t| {
+        let events = t.index.range_query(0..t.track.block_locs.len()).count;
+        (&t.meta, events, t.track.start_time(&trace.instant_pool), t.track.after_last_time(&trace.instant_pool))
+    });
+    let rows = tracks.map(|row| (false, row)).chain(instant_tracks.map(|row| (true, row))).collect::<Vec<_>>();
+    let event_memory = trace.pool.memory_usage() + trace.instant_pool.memory_usage();
+    let index_memory = trace.tracks.iter()
+        .map(|t| t.zoom_index.memory_usage() + t.busy_index.memory_usage() + t.block_starts.len() * mem::size_of::<usize>())
+        .chain(trace.instant_tracks.iter().map(|t| t.index.memory_usage()))
+        .sum::<usize>();
+    let total_events = rows.iter().map(|(_, row)| row.1).sum::<usize>();
+
+    if args.json {
+        let tracks = rows.iter().map(|(instant, (meta, events, start, end))| Json::obj(vec![
+            ("name", meta.name.as_str().into()),
+            ("instant", Json::Bool(*instant)),
+            ("pid", meta.pid.map(u64::from).into()),
+            ("tid", meta.tid.map(u64::from).into()),
+            ("process_name", meta.process_name.as_deref().into()),
+            ("lane", meta.lane.map(u64::from).into()),
+            ("events", (*events).into()),
+            ("start", (*start).into()),
+            ("end", (*end).into()),
+        ]));
+        let out = Json::obj(vec![
+            ("clock", format!("{:?}", trace.time_base.clock).as_str().into()),
+            ("base", trace.time_base.base.into()),
+            ("start", bounds.start.into()),
+            ("end", bounds.end.into()),
+            ("events", total_events.into()),
+            ("kinds", trace.kinds.len().into()),
+            ("event_bytes", event_memory.into()),
+            ("index_bytes", index_memory.into()),
+            ("tracks", Json::Arr(tracks.collect())),
+        ]);
+        println!("{}", out);
+        return Ok(());
+    }
+
+    println!("{} tracks, {} instant tracks, {} events, {} kinds",
+        trace.tracks.len(), trace.instant_tracks.len(), total_events, trace.kinds.len());
+    println!("time {}..{} ({:?} clock, base {}ns)",
+        format_time(bounds.start), format_time(bounds.end), trace.time_base.clock, trace.time_base.base);
+    println!("memory {} events, {} indexes", format_bytes(event_memory), format_bytes(index_memory));
+    println!();
+    println!("{:>9} {:>10} {:>10}  track", "events", "start", "end");
+    for (instant, (meta, events, start, end)) in &rows {
+        let mut name = match &meta.process_name {
+            Some(process) => format!("{} / {}", process, meta.name),
+            None => meta.name.clone(),
+        };
+        if let Some(lane) = meta.lane {
+            name += &format!(" [lane {}]", lane);
+        }
+        if *instant {
+            name += " [instants]";
+        }
+        let time = |t: &Option<Ns>| t.map_or("-".to_owned(), format_time);
+        println!("{:>9} {:>10} {:>10}  {}", events, time(start), time(end), name);
+    }
+    Ok(())
+}
+
+fn convert(args: &Args) -> Result<(), String> {
+    let (input, output) = (args.file(1)?, args.file(2)?);
+    let format = match args.options.get("to") {
+        Some(format) => format.as_str(),
+        None if output.ends_with(".json") => "chrome",
+        None => "native",
+    };
+    let trace = load(input)?;
+    let file = fs::File::create(output).map_err(|e| format!("{}: {}", output, e))?;
+    let mut out = BufWriter::new(file);
+    let res = match format {
+        "chrome" => write_chrome_json(&trace, &mut out),
+        "native" => trace.write_native(&mut out),
+        _ => return Err(format!("unknown format {}, expected chrome or native", format)),
+    };
+    res.and_then(|_| out.flush().map_err(Into::into)).map_err(|e| format!("{}: {}", output, e))
+}
+
+fn stats(args: &Args) -> Result<(), String> {
+    let trace = load(args.file(1)?)?;
+    let range = args.option("range", parse_range)?
+        .or_else(|| trace.time_bounds())
+        .unwrap_or(0..0);
+    let mut by_kind: HashMap<u16, KindTime> = HashMap::new();
+    for t in &trace.tracks {
+        for k in self_time_by_kind(&t.track, &trace.pool, range.clone()) {
+            let sum = by_kind.entry(k.kind).or_insert(KindTime { kind: k.kind, ..KindTime::default() });
+            sum.count += k.count;
+            sum.total_time += k.total_time;
+            sum.self_time += k.self_time;
+        }
+    }
+    let mut kinds = by_kind.into_values().collect::<Vec<_>>();
+    kinds.sort_by_key(|k| std::cmp::Reverse(k.self_time));
+
+    if args.json {
+        let rows = kinds.iter().map(|k| Json::obj(vec![
+            ("kind", trace.kinds.display(k.kind).as_ref().into()),
+            ("count", k.count.into()),
+            ("total", k.total_time.into()),
+            ("self", k.self_time.into()),
+            ("mean", (k.total_time as f64 / k.count as f64).into()),
+        ]));
+        println!("{}", Json::Arr(rows.collect()));
+        return Ok(());
+    }
+    println!("{:>9} {:>10} {:>10} {:>10}  kind", "count", "total", "self", "mean");
+    for k in &kinds {
+        println!("{:>9} {:>10} {:>10} {:>10}  {}", k.count, format_time(k.total_time), format_time(k.self_time),
+            format_time(k.total_time / k.count as u64), trace.kinds.display(k.kind));
+    }
+    Ok(())
+}
+
+fn query(args: &Args) -> Result<(), String> {
+    let trace = load(args.file(1)?)?;
+    let i = args.option("track", |s| s.parse::<usize>().ok())?.ok_or("query needs --track")?;
+    let track = trace.tracks.get(i).ok_or_else(|| format!("no track {}, there are {}", i, trace.tracks.len()))?;
+    let range = args.option("range", parse_range)?
+        .or_else(|| trace.time_bounds())
+        .unwrap_or(0..1);
+    let step = match (args.option("step", parse_time)?, args.option("buckets", |s| s.parse::<u64>().ok())?) {
+        (Some(step), _) => step.max(1),
+        (None, buckets) => (range.end - range.start).div_ceil(buckets.unwrap_or(20).max(1)).max(1),
+    };
+    let metric = args.options.get("metric").map_or("count", |m| m.as_str());
+    let starts = (range.start..range.end).step_by(step as usize);
+
+    // Bucket 0 of `aggregate_by_steps` holds everything before the range
+    let values: Vec<Json> = match metric {
+        "count" => {
+            track.event_counts(&trace.pool, range.clone(), step).into_iter().map(Json::from).collect()
+        }
+        "busy" => track.busy_fractions(&trace.pool, range.clone(), step).into_iter().map(Json::from).collect(),
+        "longest" => {
+            let longest: Vec<LongestEvent> = aggregate_by_steps(&trace.pool, &track.track.block_locs, &track.zoom_index, range.clone(), step);
+            starts.clone().enumerate().map(|(b, _)| match longest.get(b + 1).and_then(|l| l.0) {
+                Some(ev) => Json::obj(vec![
+                    ("kind", trace.kinds.display(ev.kind).as_ref().into()),
+                    ("ts", ev.ts.unpack().into()),
+                    ("dur", ev.dur.unpack().into()),
+                ]),
+                None => Json::Null,
+            }).collect()
+        }
+        _ => return Err(format!("unknown metric {}, expected count, busy or longest", metric)),
+    };
+
+    if args.json {
+        let rows = starts.zip(values).map(|(start, value)| Json::obj(vec![("start", start.into()), ("value", value)]));
+        println!("{}", Json::Arr(rows.collect()));
+        return Ok(());
+    }
+    println!("{:>10}  {}", "start", metric);
+    for (start, value) in starts.zip(values) {
+        let value = match (&value, value.as_f64()) {
+            (Json::Null, _) => "-".to_owned(),
+            (_, Some(x)) if metric == "busy" => format!("{:.1}%", x * 100.0),
+            (_, Some(x)) => x.to_string(),
+            (ev, None) => format!("{} at {} for {}",
+                ev.get("kind").and_then(Json::as_str).unwrap_or(""),
+                format_time(ev.get("ts").and_then(Json::as_u64).unwrap_or(0)),
+                format_time(ev.get("dur").and_then(Json::as_u64).unwrap_or(0))),
+        };
+        println!("{:>10}  {}", format_time(start), value);
+    }
+    Ok(())
+}
+
+fn render(args: &Args) -> Result<(), String> {
+    let (input, output) = (args.file(1)?, args.file(2)?);
+    let trace = load(input)?;
+    let range = args.option("range", parse_range)?
+        .or_else(|| trace.time_bounds())
+        .unwrap_or(0..1);
+    let parse_size = |s: &str| {
+        let (w, h) = s.split_once('x')?;
+        Some((w.parse().ok()?, h.parse().ok()?))
+    };
+    let (width, height) = args.option("size", parse_size)?.unwrap_or((1200, 600));
+    let snap = Snapshot::new(&trace, range, width, height);
+    let file = fs::File::create(output).map_err(|e| format!("{}: {}", output, e))?;
+    let mut out = BufWriter::new(file);
+    let res = match output.rsplit('.').next() {
+        Some("svg") => snap.write_svg(&mut out),
+        Some("png") => snap.write_png(&mut out),
+        Some("ppm") => snap.write_ppm(&mut out),
+        _ => return Err(format!("{}: expected a .svg, .png or .ppm file", output)),
+    };
+    res.and_then(|_| out.flush().map_err(Into::into)).map_err(|e| format!("{}: {}", output, e))
+}
+
+fn main() {
+    let res = Args::parse(std::env::args().skip(1)).and_then(|args| {
+        match args.positional.first().map(|s| s.as_str()) {
+            Some("info") => info(&args),
+            Some("convert") => convert(&args),
+            Some("stats") => stats(&args),
+            Some("query") => query(&args),
+            Some("render") => render(&args),
+            _ => Err(USAGE.to_owned()),
+        }
+    });
+    if let Err(msg) = res {
+        eprintln!("{}", msg);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,248 @@
+use crate::index::{Aggregate, LongestEvent};
+use crate::trace::{Ns, TraceEvent};
+use crate::view::{self, DrawItem, ViewMap, ViewQuant};
+use crate::{aggregate_by_steps, Trace, TrackInfo};
+use std::collections::HashMap;
+use std::mem;
+use std::ops::Range;
+
+/// The buckets of one track at one step, for a contiguous run of buckets
+/// starting at bucket `first`, i.e. time `first * step`.
+struct Entry {
+    first: u64,
+    /// The longest event starting before bucket `first`
+    before: Option<TraceEvent>,
+    longest: Vec<Option<TraceEvent>>,
+    busy: Vec<f64>,
+    last_used: u64,
+}
+
+impl Entry {
+    fn end(&self) -> u64 {
+        self.first + self.longest.len() as u64
+    }
+
+    fn bytes(&self) -> usize {
+        mem::size_of::<Entry>()
+            + self.longest.len() * mem::size_of::<Option<TraceEvent>>()
+            + self.busy.len() * mem::size_of::<f64>()
+    }
+}
+
+fn longest_of(a: Option<TraceEvent>, b: Option<TraceEvent>) -> Option<TraceEvent> {
+    <LongestEvent as Aggregate>::combine(&LongestEvent(a), &LongestEvent(b)).0
+}
+
+/// Queries buckets `first..end` of `track`, giving the longest event before
+/// them, and the longest event and busy fraction of each. The caller checks
+/// that `end * step` fits.
+fn query(trace: &Trace, track: &TrackInfo, first: u64, end: u64, step: Ns) -> (Option<TraceEvent>, Vec<Option<TraceEvent>>, Vec<f64>) {
+    let span = first * step..end * step;
+    let longest = aggregate_by_steps(&trace.pool, &track.track.block_locs, &track.zoom_index, span.clone(), step);
+    let busy = track.busy_fractions(&trace.pool, span, step);
+    let n = (end - first) as usize;
+    let before = longest.first().and_then(|l| l.0);
+    let mut buckets = longest.iter().skip(1).take(n).map(|l| l.0).collect::<Vec<_>>();
+    // The track may have ended before the span did
+    buckets.resize(n, None);
+    (before, buckets, busy)
+}
+
+/// Buckets of a view from `BucketCache::buckets`.
+pub struct Buckets<'a> {
+    /// The longest event starting before the first bucket
+    pub before: Option<TraceEvent>,
+    pub longest: &'a [Option<TraceEvent>],
+    pub busy: &'a [f64],
+}
+
+/// Keeps the buckets of each track at each step between frames. Since
+/// `ViewQuant` snaps bucket boundaries to multiples of a power of two step,
+/// panning reuses all but the buckets scrolled into view, and zooming back
+/// to a previous level finds its buckets still there.
+///
+/// Least recently used entries are evicted once the buckets take more than
+/// the memory budget, except that the entry being used is always kept.
+pub struct BucketCache {
+    entries: HashMap<(usize, Ns), Entry>,
+    budget: usize,
+    used: usize,
+    tick: u64,
+    /// Buckets computed by querying the index, for measuring hit rates
+    pub queried: u64,
+}
+
+impl BucketCache {
+    pub fn new(budget_bytes: usize) -> Self {
+        BucketCache { entries: HashMap::new(), budget: budget_bytes, used: 0, tick: 0, queried: 0 }
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.used
+    }
+
+    /// For when the trace changes.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.used = 0;
+    }
+
+    /// The buckets of `trace.tracks[track]` covering `span`, querying only
+    /// the ones which aren't cached. `None` if `span` doesn't start and end
+    /// on multiples of `step`, like a quantized view cut short at `Ns::MAX`.
+    pub fn buckets(&mut self, trace: &Trace, track: usize, span: Range<Ns>, step: Ns) -> Option<Buckets<'_>> {
+        let info = &trace.tracks[track];
+        let (first, end) = (span.start / step, span.end / step);
+        if first.checked_mul(step) != Some(span.start) || end.checked_mul(step) != Some(span.end) {
+            return None;
+        }
+        self.tick += 1;
+        let key = (track, step);
+        let mut entry = match self.entries.remove(&key) {
+            Some(entry) => {
+                self.used -= entry.bytes();
+                entry
+            }
+            None => Entry { first, before: None, longest: vec![], busy: vec![], last_used: 0 },
+        };
+
+        if entry.longest.is_empty() || end < entry.first || first > entry.end() {
+            // Nothing to reuse
+            let (before, longest, busy) = query(trace, info, first, end, step);
+            self.queried += end - first;
+            entry = Entry { first, before, longest, busy, last_used: 0 };
+        } else {
+            if first < entry.first {
+                let (before, mut longest, mut busy) = query(trace, info, first, entry.first, step);
+                self.queried += entry.first - first;
+                longest.append(&mut entry.longest);
+                busy.append(&mut entry.busy);
+                entry = Entry { first, before, longest, busy, last_used: 0 };
+            }
+            if end > entry.end() {
+                let (_, longest, busy) = query(trace, info, entry.end(), end, step);
+                self.queried += end - entry.end();
+                entry.longest.extend(longest);
+                entry.busy.extend(busy);
+            }
+            // Panning far in one direction would grow the entry forever, so
+            // keep at most a few views' worth around the current one
+            let keep = 4 * (end - first).max(1);
+            if entry.end() - first > keep {
+                let len = (first + keep - entry.first) as usize;
+                entry.longest.truncate(len);
+                entry.busy.truncate(len);
+            }
+            if end - entry.first > keep {
+                let drop = (end - keep - entry.first) as usize;
+                entry.before = entry.longest.drain(..drop).fold(entry.before, longest_of);
+                entry.busy.drain(..drop);
+                entry.first += drop as u64;
+            }
+        }
+        entry.last_used = self.tick;
+        self.used += entry.bytes();
+
+        // Evict others before putting this one back, so it can't be evicted
+        while self.used > self.budget && !self.entries.is_empty() {
+            let (&oldest, _) = self.entries.iter().min_by_key(|(_, e)| e.last_used).unwrap();
+            self.used -= self.entries.remove(&oldest).unwrap().bytes();
+        }
+        let entry = self.entries.entry(key).or_insert(entry);
+
+        let skip = (first - entry.first) as usize;
+        let n = (end - first) as usize;
+        Some(Buckets {
+            before: entry.longest[..skip].iter().copied().fold(entry.before, longest_of),
+            longest: &entry.longest[skip..skip + n],
+            busy: &entry.busy[skip..skip + n],
+        })
+    }
+
+    /// Like `view::track_items`, but from the cache.
+    pub fn track_items(&mut self, trace: &Trace, track: usize, view: &Range<Ns>, width: f64) -> Vec<DrawItem> {
+        if width <= 0.0 || view.start >= view.end {
+            return vec![];
+        }
+        let map = ViewMap::new(view, width);
+        let quant = ViewQuant::new(view, width);
+        let quantized = quant.quantize(view);
+        let Some(b) = self.buckets(trace, track, quantized.clone(), quant.time_step) else {
+            return view::track_items(trace, &trace.tracks[track], view, width);
+        };
+        let longest = Some(b.before).into_iter().chain(b.longest.iter().copied());
+        view::bucket_items(&map, &quant, &quantized, longest, b.busy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pan_and_zoom() {
+        let trace = Trace::demo_trace(3, 50_000);
+        let bounds = trace.time_bounds().unwrap();
+        let len = (bounds.end - bounds.start) / 10;
+        let width = 400.0;
+        let mut cache = BucketCache::new(1 << 20);
+
+        let mut view = bounds.start + len..bounds.start + 2 * len;
+        let step = ViewQuant::new(&view, width).time_step;
+        assert_eq!(cache.track_items(&trace, 1, &view, width), view::track_items(&trace, &trace.tracks[1], &view, width));
+        let first_query = cache.queried;
+
+        // Panning a few buckets either way only queries the new ones
+        for &shift in &[3i64, 5, -20, 40] {
+            let delta = shift.unsigned_abs().checked_mul(step).unwrap();
+            let before = cache.queried;
+            view = if shift > 0 { view.start + delta..view.end + delta } else { view.start - delta..view.end - delta };
+            assert_eq!(cache.track_items(&trace, 1, &view, width), view::track_items(&trace, &trace.tracks[1], &view, width));
+            assert!(cache.queried - before <= shift.unsigned_abs() + 1, "{} for {}", cache.queried - before, shift);
+        }
+
+        // Zooming in and back out reuses the first level
+        let zoomed = view.start..view.start + len / 4;
+        assert_eq!(cache.track_items(&trace, 1, &zoomed, width), view::track_items(&trace, &trace.tracks[1], &zoomed, width));
+        let before = cache.queried;
+        cache.track_items(&trace, 1, &view, width);
+        assert_eq!(cache.queried, before);
+
+        // Jumping far away queries everything
+        let far = bounds.end - len..bounds.end;
+        let before = cache.queried;
+        assert_eq!(cache.track_items(&trace, 1, &far, width), view::track_items(&trace, &trace.tracks[1], &far, width));
+        assert!(cache.queried - before >= first_query - 1);
+    }
+
+    #[test]
+    fn eviction() {
+        let trace = Trace::demo_trace(10, 5_000);
+        let bounds = trace.time_bounds().unwrap();
+        let mut cache = BucketCache::new(20_000);
+        for i in 0..10 {
+            cache.track_items(&trace, i, &bounds, 500.0);
+            assert!(cache.memory_usage() <= 20_000 || cache.entries.len() == 1);
+        }
+        assert!(cache.entries.len() < 10);
+        // The most recent is kept
+        assert!(cache.entries.keys().any(|&(track, _)| track == 9));
+
+        // An entry bigger than the budget on its own is still kept
+        let mut tiny = BucketCache::new(0);
+        assert_eq!(tiny.track_items(&trace, 0, &bounds, 500.0), view::track_items(&trace, &trace.tracks[0], &bounds, 500.0));
+        assert_eq!(tiny.entries.len(), 1);
+    }
+
+    #[test]
+    fn huge_steps() {
+        let trace = Trace::demo_trace(1, 1000);
+        let mut cache = BucketCache::new(1 << 20);
+        for view in [0..u64::MAX, 1 << 62..u64::MAX, u64::MAX - (1 << 40)..u64::MAX] {
+            assert_eq!(cache.track_items(&trace, 0, &view, 1000.0), view::track_items(&trace, &trace.tracks[0], &view, 1000.0));
+        }
+        // Spans past the last whole bucket skip the cache
+        assert!(cache.buckets(&trace, 0, 0..u64::MAX, 1 << 60).is_none());
+        assert!(cache.entries.is_empty());
+    }
+}
@@ -0,0 +1,378 @@
+use crate::args::EventArgs;
+use crate::clock::{AbsNs, ClockDomain, TimeBase};
+use crate::error::{Error, Result};
+use crate::instant::InstantEvent;
+use crate::json::Json;
+use crate::meta::TrackMeta;
+use crate::trace::{Ns, Track};
+use crate::{Trace, TrackInfo};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::mem;
+
+type Args = Vec<(String, Json)>;
+/// Absolute start, duration and name
+type Span = (AbsNs, Ns, String);
+
+#[derive(Default)]
+struct Thread {
+    name: Option<String>,
+    sort_index: i64,
+    slices: Vec<(Span, Args)>,
+    open: Vec<(AbsNs, String, Args)>,
+    instants: Vec<(AbsNs, String)>,
+    color: Option<[u8; 3]>,
+}
+
+/// The colors Chrome's viewer has for the reserved `cname` values
+const CNAME_COLORS: &[(&str, [u8; 3])] = &[
+    ("thread_state_uninterruptible", [182, 125, 143]),
+    ("thread_state_iowait", [255, 140, 0]),
+    ("thread_state_running", [126, 200, 148]),
+    ("thread_state_runnable", [133, 160, 210]),
+    ("thread_state_unknown", [199, 155, 125]),
+    ("generic_work", [125, 125, 125]),
+    ("good", [0, 125, 0]),
+    ("bad", [180, 125, 0]),
+    ("terrible", [180, 0, 0]),
+    ("black", [0, 0, 0]),
+    ("grey", [221, 221, 221]),
+    ("white", [255, 255, 255]),
+    ("yellow", [255, 255, 0]),
+    ("olive", [100, 100, 0]),
+    ("rail_response", [67, 135, 253]),
+    ("rail_animation", [244, 74, 63]),
+    ("rail_idle", [238, 142, 0]),
+    ("rail_load", [13, 168, 97]),
+    ("startup", [230, 230, 0]),
+];
+
+/// Chrome times are floating point microseconds
+fn to_ns(us: f64) -> AbsNs {
+    (us * 1000.0).round().max(0.0) as AbsNs
+}
+
+fn str_field<'a>(ev: &'a Json, key: &str) -> &'a str {
+    ev.get(key).and_then(Json::as_str).unwrap_or("")
+}
+
+fn args_of(ev: &Json) -> Args {
+    match ev.get("args") {
+        Some(Json::Obj(fields)) => fields.clone(),
+        _ => vec![],
+    }
+}
+
+/// Imports a trace in the [Chrome trace event format], either a bare array
+/// of events or an object with a `traceEvents` array.
+///
+/// Complete (`X`) and begin/end (`B`/`E`) events become slices on a track
+/// per thread, instant (`i`/`I`) events go on an instant track per thread,
+/// and async (`b`/`e`, `S`/`F`) spans become an async track per process and
+/// category. Thread and process names and thread sort indices come from
+/// metadata (`M`) events, and the color of a thread's tracks from the first
+/// of its events with a `cname`. Other events are ignored. Times are relative to the
+/// earliest event, since Chrome doesn't say which clock they're from.
+///
+/// The `args` of slices are kept in `TrackInfo::args`, with those of an `E`
+/// event added to its `B` event's. Async spans are moved between lanes, so
+/// their args are dropped.
+///
+/// [Chrome trace event format]: https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+pub fn import_chrome_json(text: &str) -> Result<Trace> {
+    let root = Json::parse(text)?;
+    let events = root.as_array()
+        .or_else(|| root.get("traceEvents").and_then(Json::as_array))
+        .ok_or_else(|| Error::InvalidFormat("expected an array of trace events".into()))?;
+
+    let mut threads: BTreeMap<(u32, u32), Thread> = BTreeMap::new();
+    let mut process_names: HashMap<u32, String> = HashMap::new();
+    let mut async_open: HashMap<(u32, String, String), Vec<(AbsNs, String)>> = HashMap::new();
+    let mut async_spans: BTreeMap<(u32, String), Vec<Span>> = BTreeMap::new();
+    let mut last_time = 0;
+    for ev in events {
+        let pid = ev.get("pid").and_then(Json::as_u64).unwrap_or(0) as u32;
+        let tid = ev.get("tid").and_then(Json::as_u64).unwrap_or(0) as u32;
+        let name = str_field(ev, "name").to_owned();
+        let ts = ev.get("ts").and_then(Json::as_f64).map(to_ns);
+        let args = ev.get("args");
+        // Threads with no events are dropped when building tracks
+        let thread = threads.entry((pid, tid)).or_default();
+        if thread.color.is_none() {
+            let cname = str_field(ev, "cname");
+            thread.color = CNAME_COLORS.iter().find(|c| c.0 == cname).map(|c| c.1);
+        }
+        match (str_field(ev, "ph"), ts) {
+            ("M", _) => {
+                let arg = |key| args.and_then(|a| a.get(key));
+                match name.as_str() {
+                    "process_name" => {
+                        if let Some(n) = arg("name").and_then(Json::as_str) {
+                            process_names.insert(pid, n.to_owned());
+                        }
+                    }
+                    "thread_name" => thread.name = arg("name").and_then(Json::as_str).map(str::to_owned),
+                    "thread_sort_index" => {
+                        thread.sort_index = arg("sort_index").and_then(Json::as_f64).unwrap_or(0.0) as i64;
+                    }
+                    _ => {}
+                }
+            }
+            ("X", Some(ts)) => {
+                let dur = ev.get("dur").and_then(Json::as_f64).map_or(0, to_ns);
+                last_time = last_time.max(ts + dur);
+                thread.slices.push(((ts, dur, name), args_of(ev)));
+            }
+            ("B", Some(ts)) => {
+                last_time = last_time.max(ts);
+                thread.open.push((ts, name, args_of(ev)));
+            }
+            ("E", Some(ts)) => {
+                last_time = last_time.max(ts);
+                if let Some((start, name, mut args)) = thread.open.pop() {
+                    args.extend(args_of(ev));
+                    thread.slices.push(((start, ts.saturating_sub(start), name), args));
+                }
+            }
+            ("i", Some(ts)) | ("I", Some(ts)) => {
+                last_time = last_time.max(ts);
+                thread.instants.push((ts, name));
+            }
+            (ph @ ("b" | "e" | "S" | "F"), Some(ts)) => {
+                last_time = last_time.max(ts);
+                let cat = str_field(ev, "cat").to_owned();
+                let id = ev.get("id").or_else(|| ev.get("id2")).map(|id| id.to_string()).unwrap_or_default();
+                let open = async_open.entry((pid, cat.clone(), id)).or_default();
+                if ph == "b" || ph == "S" {
+                    open.push((ts, name));
+                } else if let Some((start, name)) = open.pop() {
+                    async_spans.entry((pid, cat)).or_default().push((start, ts.saturating_sub(start), name));
+                }
+            }
+            _ => {}
+        }
+    }
+    // Close anything left open at the end of the trace
+    for thread in threads.values_mut() {
+        for (start, name, args) in thread.open.drain(..) {
+            thread.slices.push(((start, last_time - start, name), args));
+        }
+    }
+
+    let base = threads.values()
+        .flat_map(|t| t.slices.iter().map(|s| s.0 .0).chain(t.instants.iter().map(|i| i.0)))
+        .chain(async_spans.values().flat_map(|spans| spans.iter().map(|s| s.0)))
+        .min()
+        .unwrap_or(0);
+    let mut trace = Trace::new();
+    trace.time_base = TimeBase::new(ClockDomain::Unknown, base);
+
+    for (&(pid, tid), thread) in &mut threads {
+        let meta = TrackMeta {
+            name: thread.name.clone().unwrap_or_else(|| format!("Thread {}", tid)),
+            pid: Some(pid),
+            tid: Some(tid),
+            process_name: process_names.get(&pid).cloned(),
+            sort_key: thread.sort_index,
+            color: thread.color,
+            ..TrackMeta::default()
+        };
+        if !thread.slices.is_empty() {
+            // Parents before the children starting at the same time
+            thread.slices.sort_by_key(|((ts, dur, _), _)| (*ts, Reverse(*dur)));
+            let mut track = Track::new();
+            let mut args = EventArgs::new();
+            for (i, ((ts, dur, name), ev_args)) in thread.slices.drain(..).enumerate() {
+                let kind = trace.kinds.try_intern(&name)?;
+                track.try_push(&mut trace.pool, trace.time_base.event(kind, ts, dur)?)?;
+                args.insert(i, ev_args);
+            }
+            trace.tracks.push(TrackInfo::new(track, &trace.pool).with_meta(meta.clone()).with_args(args));
+        }
+        if !thread.instants.is_empty() {
+            thread.instants.sort_by_key(|i| i.0);
+            let mut instants = Vec::with_capacity(thread.instants.len());
+            for (ts, name) in &thread.instants {
+                let kind = trace.kinds.try_intern(name)?;
+                instants.push(InstantEvent::new(kind, trace.time_base.to_relative(*ts)?)?);
+            }
+            trace.add_instant_track(meta, instants)?;
+        }
+    }
+    for ((pid, cat), spans) in async_spans {
+        let mut events = Vec::with_capacity(spans.len());
+        for (ts, dur, name) in &spans {
+            let kind = trace.kinds.try_intern(name)?;
+            events.push(trace.time_base.event(kind, *ts, *dur)?);
+        }
+        let meta = TrackMeta {
+            name: if cat.is_empty() { "async".to_owned() } else { cat },
+            pid: Some(pid),
+            process_name: process_names.get(&pid).cloned(),
+            // After the threads of the process
+            sort_key: i64::MAX,
+            ..TrackMeta::default()
+        };
+        trace.add_async_track(meta, events)?;
+    }
+    Ok(trace)
+}
+
+/// Microseconds with all the digits of the nanoseconds, which `f64` doesn't
+/// have room for with epoch times.
+fn us(ns: AbsNs) -> String {
+    format!("{}.{:03}", ns / 1000, ns % 1000)
+}
+
+/// Writes `trace` in the Chrome trace event format, so it can be opened in
+/// other viewers. Async tracks are written as async spans with the track
+/// name as the category.
+pub fn write_chrome_json(trace: &Trace, out: &mut impl Write) -> Result<()> {
+    let mut first = true;
+    let mut sep = |out: &mut dyn Write| out.write_all(if mem::take(&mut first) { b"\n" } else { b",\n" });
+    let ids = |meta: &TrackMeta| (Json::from(meta.pid.unwrap_or(0) as u64), Json::from(meta.tid.unwrap_or(0) as u64));
+    let metadata = |meta: &TrackMeta, name: &str, args: Json| {
+        let (pid, tid) = ids(meta);
+        let mut fields = vec![("ph", "M".into()), ("name", name.into()), ("pid", pid), ("tid", tid), ("args", args)];
+        // Only colors that have a name survive
+        if let Some(&(cname, _)) = CNAME_COLORS.iter().find(|c| Some(c.1) == meta.color) {
+            fields.push(("cname", cname.into()));
+        }
+        Json::obj(fields)
+    };
+
+    out.write_all(b"{\"traceEvents\":[")?;
+    let mut named_processes = vec![];
+    let mut named_threads = vec![];
+    for meta in trace.tracks.iter().map(|t| &t.meta).chain(trace.instant_tracks.iter().map(|t| &t.meta)) {
+        if let (Some(pid), Some(name)) = (meta.pid, &meta.process_name) {
+            if !named_processes.contains(&(meta.source, pid)) {
+                named_processes.push((meta.source, pid));
+                sep(out)?;
+                write!(out, "{}", metadata(meta, "process_name", Json::obj(vec![("name", name.as_str().into())])))?;
+            }
+        }
+        // A thread with instants has two tracks with the same meta
+        if meta.lane.is_none() && !named_threads.contains(&(meta.source, meta.pid, meta.tid)) {
+            named_threads.push((meta.source, meta.pid, meta.tid));
+            sep(out)?;
+            write!(out, "{}", metadata(meta, "thread_name", Json::obj(vec![("name", meta.name.as_str().into())])))?;
+            if meta.sort_key != 0 {
+                sep(out)?;
+                write!(out, "{}", metadata(meta, "thread_sort_index", Json::obj(vec![("sort_index", (meta.sort_key as f64).into())])))?;
+            }
+        }
+    }
+
+    let mut async_id = 0u64;
+    for info in &trace.tracks {
+        let (pid, tid) = ids(&info.meta);
+        for (i, ev) in info.track.events(&trace.pool).enumerate() {
+            let name = Json::from(trace.kinds.display(ev.kind).as_ref());
+            let ts = trace.time_base.to_absolute(ev.ts.unpack());
+            let dur = ev.dur.unpack();
+            // Times are written raw so they don't lose precision
+            if info.meta.lane.is_some() {
+                async_id += 1;
+                let cat = Json::from(info.meta.name.as_str());
+                sep(out)?;
+                write!(out, "{{\"ph\":\"b\",\"cat\":{},\"id\":{},\"name\":{},\"pid\":{},\"ts\":{}}}", cat, async_id, name, pid, us(ts))?;
+                write!(out, ",\n{{\"ph\":\"e\",\"cat\":{},\"id\":{},\"name\":{},\"pid\":{},\"ts\":{}}}", cat, async_id, name, pid, us(ts + dur))?;
+            } else {
+                sep(out)?;
+                write!(out, "{{\"ph\":\"X\",\"name\":{},\"pid\":{},\"tid\":{},\"ts\":{},\"dur\":{}", name, pid, tid, us(ts), us(dur))?;
+                let args = info.args.get(i);
+                if !args.is_empty() {
+                    write!(out, ",\"args\":{}", Json::Obj(args.to_vec()))?;
+                }
+                out.write_all(b"}")?;
+            }
+        }
+    }
+    for info in &trace.instant_tracks {
+        let (pid, tid) = ids(&info.meta);
+        for ev in info.track.events(&trace.instant_pool) {
+            let name = Json::from(trace.kinds.display(ev.kind).as_ref());
+            let ts = trace.time_base.to_absolute(ev.ts.unpack());
+            sep(out)?;
+            write!(out, "{{\"ph\":\"i\",\"s\":\"t\",\"name\":{},\"pid\":{},\"tid\":{},\"ts\":{}}}", name, pid, tid, us(ts))?;
+        }
+    }
+    out.write_all(b"\n]}\n")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE: &str = r#"{"traceEvents": [
+        {"ph": "M", "name": "process_name", "pid": 1, "args": {"name": "browser"}},
+        {"ph": "M", "name": "thread_name", "pid": 1, "tid": 2, "args": {"name": "main"}, "cname": "good"},
+        {"ph": "X", "name": "outer", "pid": 1, "tid": 2, "ts": 1000.5, "dur": 10, "args": {"url": "a.html"}},
+        {"ph": "B", "name": "inner", "pid": 1, "tid": 2, "ts": 1000.5, "args": {"n": 1}},
+        {"ph": "E", "pid": 1, "tid": 2, "ts": 1003, "args": {"ok": true}},
+        {"ph": "i", "name": "vsync", "pid": 1, "tid": 2, "ts": 1004, "s": "t"},
+        {"ph": "b", "name": "fetch", "cat": "net", "id": "0x1", "pid": 1, "ts": 1001},
+        {"ph": "b", "name": "fetch", "cat": "net", "id": "0x2", "pid": 1, "ts": 1002},
+        {"ph": "e", "name": "fetch", "cat": "net", "id": "0x1", "pid": 1, "ts": 1005},
+        {"ph": "e", "name": "fetch", "cat": "net", "id": "0x2", "pid": 1, "ts": 1006},
+        {"ph": "B", "name": "unfinished", "pid": 1, "tid": 3, "ts": 1008},
+    "#;
+
+    fn slices(trace: &Trace, i: usize) -> Vec<(String, Ns, Ns)> {
+        trace.tracks[i].track.events(&trace.pool)
+            .map(|ev| (trace.kinds.display(ev.kind).into_owned(), ev.ts.unpack(), ev.dur.unpack()))
+            .collect()
+    }
+
+    #[test]
+    fn import() {
+        let trace = import_chrome_json(TRACE).unwrap();
+        assert_eq!(trace.time_base.base, 1_000_500);
+        assert_eq!(trace.tracks.len(), 4);
+        assert_eq!(trace.tracks[0].meta.name, "main");
+        assert_eq!(trace.tracks[0].meta.process_name.as_deref(), Some("browser"));
+        assert_eq!(trace.tracks[0].meta.color, Some([0, 125, 0]));
+        assert_eq!(trace.tracks[1].meta.color, None);
+        assert_eq!(slices(&trace, 0), vec![("outer".into(), 0, 10_000), ("inner".into(), 0, 2_500)]);
+        assert_eq!(trace.tracks[0].args.get(0), &[("url".to_owned(), Json::from("a.html"))]);
+        assert_eq!(trace.tracks[0].args.get(1), &[("n".to_owned(), Json::Num(1.0)), ("ok".to_owned(), Json::Bool(true))]);
+        assert!(trace.tracks[1].args.is_empty());
+        assert_eq!(slices(&trace, 1), vec![("unfinished".into(), 7_500, 2_500)]);
+        assert_eq!(slices(&trace, 2), vec![("fetch".into(), 500, 4_000)]);
+        assert_eq!(slices(&trace, 3), vec![("fetch".into(), 1_500, 4_000)]);
+        assert_eq!(trace.tracks[3].meta.lane, Some(1));
+        let instants = trace.instant_tracks[0].track.events(&trace.instant_pool).map(|ev| ev.ts.unpack()).collect::<Vec<_>>();
+        assert_eq!(instants, vec![3_500]);
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let trace = import_chrome_json(TRACE).unwrap();
+        let mut out = vec![];
+        write_chrome_json(&trace, &mut out).unwrap();
+        let again = import_chrome_json(std::str::from_utf8(&out).unwrap()).unwrap();
+        assert_eq!(again.time_base, trace.time_base);
+        assert_eq!(again.tracks.len(), trace.tracks.len());
+        for i in 0..trace.tracks.len() {
+            assert_eq!(slices(&again, i), slices(&trace, i));
+            assert_eq!(again.tracks[i].meta.name, trace.tracks[i].meta.name);
+            assert_eq!(again.tracks[i].meta.color, trace.tracks[i].meta.color);
+            assert_eq!(again.tracks[i].args, trace.tracks[i].args);
+        }
+        assert_eq!(again.instant_tracks.len(), 1);
+    }
+
+    #[test]
+    fn too_many_names() {
+        let mut json = String::from("[");
+        for i in 0..=u16::MAX as u32 + 1 {
+            json += &format!(r#"{{"ph": "X", "name": "n{}", "pid": 1, "tid": 1, "ts": {}, "dur": 1}},"#, i, i * 2);
+        }
+        json.pop();
+        json.push(']');
+        assert!(matches!(import_chrome_json(&json), Err(Error::InvalidFormat(_))));
+    }
+}
@@ -0,0 +1,180 @@
+use crate::analysis::{self_times, SelfTime};
+use crate::error::{Error, Result};
+use crate::flame::CallTree;
+use crate::gaps::find_gaps;
+use crate::heatmap::{Heatmap, HeatmapMetric};
+use crate::iforest::IForestIndex;
+use crate::index::{Aggregate, LargestGap};
+use crate::trace::{Ns, PackedNs, TraceEvent};
+use crate::{aggregate_by_steps, Trace};
+use std::ops::Range;
+
+/// Nanoseconds in a trace's clock domain, as opposed to `Ns` which are
+/// relative to the trace's `TimeBase`.
+pub type AbsNs = u64;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ClockDomain {
+    Unknown,
+    /// `CLOCK_MONOTONIC`
+    Monotonic,
+    /// `CLOCK_BOOTTIME`
+    BootTime,
+    /// Unix epoch nanoseconds
+    Realtime,
+}
+
+/// Events are stored as nanoseconds after `base` so that captures using
+/// clocks which are far past 2^48ns, like the Unix epoch, still fit in a
+/// `PackedNs`. The catch is a single trace can only span about 78 hours.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TimeBase {
+    pub clock: ClockDomain,
+    pub base: AbsNs,
+}
+
+impl TimeBase {
+    pub const fn new(clock: ClockDomain, base: AbsNs) -> Self {
+        TimeBase { clock, base }
+    }
+
+    pub fn to_relative(&self, ts: AbsNs) -> Result<Ns> {
+        let rel = ts.checked_sub(self.base).ok_or(Error::BeforeTimeBase { ts, base: self.base })?;
+        if rel > PackedNs::MAX {
+            return Err(Error::SpanTooLarge { ts, base: self.base });
+        }
+        Ok(rel)
+    }
+
+    /// Saturates rather than wrapping for times too far past the base,
+    /// which no event can be.
+    pub fn to_absolute(&self, ts: Ns) -> AbsNs {
+        self.base.saturating_add(ts)
+    }
+
+    /// For passing absolute query ranges to functions taking `Ns`, clamping
+    /// times before the base to it.
+    pub fn relative_range(&self, r: Range<AbsNs>) -> Range<Ns> {
+        r.start.saturating_sub(self.base)..r.end.saturating_sub(self.base)
+    }
+
+    pub fn absolute_range(&self, r: Range<Ns>) -> Range<AbsNs> {
+        self.to_absolute(r.start)..self.to_absolute(r.end)
+    }
+
+    /// Makes an event with an absolute start time, checking that it and its
+    /// end fit in the packed format.
+    pub fn event(&self, kind: u16, ts: AbsNs, dur: Ns) -> Result<TraceEvent> {
+        let rel = self.to_relative(ts)?;
+        self.to_relative(ts.saturating_add(dur))?;
+        TraceEvent::try_new(kind, rel, dur)
+    }
+}
+
+/// Versions of the query functions taking and returning absolute times,
+/// which convert through `time_base`. Ranges before the base are clamped to
+/// it since no event can start there, except that bucketed queries fail for
+/// a span starting before the base, where the buckets wouldn't line up with
+/// the stored times.
+impl Trace {
+    fn step_span(&self, time_span: Range<AbsNs>) -> Result<Range<Ns>> {
+        let base = self.time_base.base;
+        let start = time_span.start.checked_sub(base).ok_or(Error::BeforeTimeBase { ts: time_span.start, base })?;
+        Ok(start..time_span.end.saturating_sub(base).max(start))
+    }
+
+    /// `aggregate_by_steps` over the `track`th track.
+    pub fn aggregate_by_steps_abs<A: Aggregate>(&self, track: usize, index: &IForestIndex<A>, time_span: Range<AbsNs>, time_step: u64) -> Result<Vec<A>> {
+        let span = self.step_span(time_span)?;
+        Ok(aggregate_by_steps(&self.pool, &self.tracks[track].track.block_locs, index, span, time_step))
+    }
+
+    /// `TrackInfo::busy_fractions` of the `track`th track.
+    pub fn busy_fractions_abs(&self, track: usize, time_span: Range<AbsNs>, time_step: u64) -> Result<Vec<f64>> {
+        let span = self.step_span(time_span)?;
+        Ok(self.tracks[track].busy_fractions(&self.pool, span, time_step))
+    }
+
+    /// Like `heatmap`, with the absolute span in the result.
+    pub fn heatmap_abs(&self, time_span: Range<AbsNs>, time_step: u64, metric: HeatmapMetric) -> Result<Heatmap> {
+        let mut heatmap = self.heatmap(self.step_span(time_span.clone())?, time_step, metric);
+        heatmap.time_span = time_span;
+        Ok(heatmap)
+    }
+
+    /// `find_gaps` on the `track`th track, with `index` built from it.
+    pub fn find_gaps_abs(&self, track: usize, index: &IForestIndex<LargestGap>, range: Range<AbsNs>, min_gap: Ns) -> Vec<Range<AbsNs>> {
+        let range = self.time_base.relative_range(range);
+        find_gaps(&self.pool, &self.tracks[track].track, index, range, min_gap)
+            .into_iter()
+            .map(|gap| self.time_base.absolute_range(gap))
+            .collect()
+    }
+
+    /// `self_times` of the `track`th track. The events are still relative to
+    /// the base, use `time_base.to_absolute` on their times.
+    pub fn self_times_abs(&self, track: usize, range: Range<AbsNs>) -> Vec<SelfTime> {
+        self_times(&self.tracks[track].track, &self.pool, self.time_base.relative_range(range))
+    }
+
+    /// `CallTree::build` over an absolute range.
+    pub fn call_tree_abs(&self, tracks: &[usize], range: Range<AbsNs>, bottom_up: bool) -> CallTree {
+        CallTree::build(self, tracks, self.time_base.relative_range(range), bottom_up)
+    }
+}
+
+impl Default for TimeBase {
+    fn default() -> Self {
+        TimeBase::new(ClockDomain::Unknown, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::TrackIndex;
+
+    #[test]
+    fn epoch_times() {
+        let base = TimeBase::new(ClockDomain::Realtime, 1_600_000_000_000_000_000);
+        let ev = base.event(3, 1_600_000_000_000_000_123, 7).unwrap();
+        assert_eq!(ev.ts.unpack(), 123);
+        assert_eq!(base.to_absolute(ev.ts.unpack()), 1_600_000_000_000_000_123);
+        assert_eq!(base.relative_range(1_599_000_000_000_000_000..1_600_000_000_000_000_050), 0..50);
+
+        let too_late = base.base + PackedNs::MAX - 3;
+        assert_eq!(base.event(0, too_late, 10).err(), Some(Error::SpanTooLarge { ts: too_late + 10, base: base.base }));
+        assert_eq!(base.event(0, 5, 10).err(), Some(Error::BeforeTimeBase { ts: 5, base: base.base }));
+        assert_eq!(base.to_absolute(u64::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn absolute_queries() {
+        let mut trace = Trace::demo_trace(2, 2000);
+        let rel = trace.time_bounds().unwrap();
+        let step = (rel.end - rel.start) / 20;
+        trace.time_base = TimeBase::new(ClockDomain::Realtime, 1_600_000_000_000_000_000);
+        let abs = trace.absolute_time_bounds().unwrap();
+        assert_eq!(abs, trace.time_base.absolute_range(rel.clone()));
+
+        let info = &trace.tracks[1];
+        let longest = |r: Vec<crate::index::LongestEvent>| r.iter().map(|l| l.0.map(|ev| ev.dur.unpack())).collect::<Vec<_>>();
+        let expected = aggregate_by_steps(&trace.pool, &info.track.block_locs, &info.zoom_index, rel.clone(), step);
+        assert_eq!(longest(trace.aggregate_by_steps_abs(1, &info.zoom_index, abs.clone(), step).unwrap()), longest(expected));
+        assert_eq!(trace.busy_fractions_abs(1, abs.clone(), step).unwrap(), info.busy_fractions(&trace.pool, rel.clone(), step));
+        let heatmap = trace.heatmap_abs(abs.clone(), step, HeatmapMetric::EventCount).unwrap();
+        assert_eq!(heatmap.time_span, abs);
+        assert_eq!(heatmap.values, trace.heatmap(rel.clone(), step, HeatmapMetric::EventCount).values);
+        let before = trace.time_base.base - 1..abs.end;
+        assert!(matches!(trace.heatmap_abs(before.clone(), step, HeatmapMetric::EventCount), Err(Error::BeforeTimeBase { .. })));
+
+        let gaps = IForestIndex::<LargestGap>::build(&info.track, &trace.pool);
+        let expected = find_gaps(&trace.pool, &info.track, &gaps, rel.clone(), 1000);
+        let found = trace.find_gaps_abs(1, &gaps, before.clone(), 1000);
+        assert!(!found.is_empty());
+        assert_eq!(found, expected.into_iter().map(|g| trace.time_base.absolute_range(g)).collect::<Vec<_>>());
+        assert_eq!(trace.self_times_abs(1, before.clone()).len(), self_times(&info.track, &trace.pool, rel.clone()).len());
+        let tree = trace.call_tree_abs(&[0, 1], before, false);
+        assert_eq!(tree.folded_stacks(&trace.kinds), CallTree::build(&trace, &[0, 1], rel, false).folded_stacks(&trace.kinds));
+    }
+}
@@ -0,0 +1,252 @@
+use crate::trace::{BlockIndex, BlockRef, Codec, Event, Ns, PackedNs, TraceBlock, TraceEvent};
+use std::collections::HashMap;
+use std::mem;
+
+#[derive(Copy, Clone)]
+struct Header {
+    /// Into `CompressedBlocks::data`, or `OPEN` if the block is in `open`
+    offset: u64,
+    start: PackedNs,
+    len: u16,
+}
+
+const OPEN: u64 = u64::MAX;
+
+/// Storage for `BlockFormat::Compressed`. Blocks are kept raw until they
+/// fill up, then encoded as a varint per kind, per duration and per
+/// timestamp delta from the previous event in the block, after converting
+/// them to `TraceEvent`s with `codec`. Deltas are zigzag encoded since
+/// nothing stops a block's events from being out of order.
+pub(crate) struct CompressedBlocks<E: Event, const N: usize> {
+    headers: Vec<Header>,
+    data: Vec<u8>,
+    open: HashMap<BlockIndex, TraceBlock<E, N>>,
+    codec: Codec<E>,
+}
+
+pub(crate) fn write_varint(out: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        out.push((x as u8) | 0x80);
+        x >>= 7;
+    }
+    out.push(x as u8);
+}
+
+#[inline]
+fn read_varint(data: &[u8], pos: &mut usize) -> u64 {
+    let mut x = 0;
+    let mut shift = 0;
+    loop {
+        let b = data[*pos];
+        *pos += 1;
+        x |= ((b & 0x7f) as u64) << shift;
+        if b < 0x80 {
+            return x;
+        }
+        shift += 7;
+    }
+}
+
+impl<E: Event, const N: usize> CompressedBlocks<E, N> {
+    pub fn new(codec: Codec<E>) -> Self {
+        CompressedBlocks {
+            headers: vec![],
+            data: vec![],
+            open: HashMap::new(),
+            codec,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn alloc(&mut self) -> BlockIndex {
+        let i = self.headers.len() as BlockIndex;
+        self.headers.push(Header { offset: OPEN, start: PackedNs::new(0), len: 0 });
+        self.open.insert(i, TraceBlock::default());
+        i
+    }
+
+    pub fn is_full(&self, i: BlockIndex) -> bool {
+        self.headers[i as usize].offset != OPEN
+    }
+
+    pub fn push(&mut self, i: BlockIndex, ev: E) {
+        let block = self.open.get_mut(&i).expect("pushing to full block");
+        block.push(ev);
+        if block.is_full() {
+            let block = self.open.remove(&i).unwrap();
+            self.seal(i, &block);
+        }
+    }
+
+    fn seal(&mut self, i: BlockIndex, block: &TraceBlock<E, N>) {
+        let offset = self.data.len() as u64;
+        let mut prev_ts = block.start_time();
+        for ev in block.events() {
+            let ev = (self.codec.pack)(ev);
+            let ts = ev.ts.unpack();
+            write_varint(&mut self.data, ev.kind as u64);
+            let delta = ts as i64 - prev_ts as i64;
+            write_varint(&mut self.data, ((delta << 1) ^ (delta >> 63)) as u64);
+            write_varint(&mut self.data, ev.dur.unpack());
+            prev_ts = ts;
+        }
+        self.headers[i as usize] = Header { offset, start: PackedNs::new(block.start_time()), len: block.len };
+    }
+
+    pub fn start_time(&self, i: BlockIndex) -> Ns {
+        let header = &self.headers[i as usize];
+        if header.offset == OPEN {
+            self.open[&i].start_time()
+        } else {
+            header.start.unpack()
+        }
+    }
+
+    pub fn block(&self, i: BlockIndex) -> BlockRef<'_, E, N> {
+        let header = &self.headers[i as usize];
+        if header.offset == OPEN {
+            return BlockRef::Borrowed(&self.open[&i]);
+        }
+        let mut block = TraceBlock::default();
+        let mut pos = header.offset as usize;
+        let mut ts = header.start.unpack();
+        for _ in 0..header.len {
+            let kind = read_varint(&self.data, &mut pos) as u16;
+            let delta = read_varint(&self.data, &mut pos);
+            ts = (ts as i64 + ((delta >> 1) as i64 ^ -((delta & 1) as i64))) as u64;
+            let dur = read_varint(&self.data, &mut pos);
+            block.push((self.codec.unpack)(TraceEvent { kind, ts: PackedNs::new(ts), dur: PackedNs::new(dur) }));
+        }
+        BlockRef::Decoded(block)
+    }
+
+    /// Re-encodes the block after `f` modifies it. The old encoding is
+    /// overwritten if the new one fits, otherwise it's wasted.
+    pub fn modify(&mut self, i: BlockIndex, f: impl FnOnce(&mut TraceBlock<E, N>)) {
+        if let Some(block) = self.open.get_mut(&i) {
+            f(block);
+            if block.is_full() {
+                let block = self.open.remove(&i).unwrap();
+                self.seal(i, &block);
+            }
+            return;
+        }
+        let mut block = match self.block(i) {
+            BlockRef::Decoded(block) => block,
+            BlockRef::Borrowed(_) | BlockRef::Soa(..) => unreachable!(),
+        };
+        f(&mut block);
+        if !block.is_full() {
+            self.headers[i as usize].offset = OPEN;
+            self.open.insert(i, block);
+            return;
+        }
+        let old = self.headers[i as usize];
+        let old_offset = old.offset as usize;
+        let old_len = self.encoded_len_at(old_offset, old.len);
+        let old_end = self.data.len();
+        self.seal(i, &block);
+        let new_len = self.data.len() - old_end;
+        if new_len <= old_len {
+            let encoded = self.data.split_off(old_end);
+            self.data[old_offset..old_offset + new_len].copy_from_slice(&encoded);
+            self.headers[i as usize].offset = old_offset as u64;
+        }
+    }
+
+    fn encoded_len_at(&self, offset: usize, len: u16) -> usize {
+        let mut pos = offset;
+        for _ in 0..(len as usize * 3) {
+            read_varint(&self.data, &mut pos);
+        }
+        pos - offset
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.headers.capacity() * mem::size_of::<Header>()
+            + self.data.capacity()
+            + self.open.capacity() * (mem::size_of::<TraceBlock<E, N>>() + mem::size_of::<BlockIndex>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::TsSum;
+    use crate::trace::{BlockFormat, BlockPool, PackedNs, TraceEvent, Track};
+    use crate::Trace;
+    use fastrand::Rng;
+
+    #[test]
+    fn compressed_matches_raw() {
+        let mut raw = BlockPool::new();
+        let mut compressed = BlockPool::with_format(BlockFormat::Compressed);
+        let mut raw_track = Track::new();
+        let mut compressed_track = Track::new();
+        raw_track.add_dummy_events(&mut raw, &Rng::with_seed(7), 1003);
+        compressed_track.add_dummy_events(&mut compressed, &Rng::with_seed(7), 1003);
+
+        let events = |track: &Track, pool: &BlockPool| {
+            track.events(pool).map(|ev| (ev.kind, ev.ts.unpack(), ev.dur.unpack())).collect::<Vec<_>>()
+        };
+        assert_eq!(events(&raw_track, &raw), events(&compressed_track, &compressed));
+        let span = 100_000..5_000_000;
+        assert_eq!(
+            crate::aggregate_by_steps_unindexed::<TsSum, _, _>(&raw, &raw_track.block_locs, span.clone(), 10_000),
+            crate::aggregate_by_steps_unindexed::<TsSum, _, _>(&compressed, &compressed_track.block_locs, span, 10_000),
+        );
+
+        // Changing kinds changes the encoded size of blocks, both ways
+        for pool in [&mut raw, &mut compressed] {
+            for i in 0..pool.len() {
+                pool.modify(i as u32, |block| {
+                    for ev in block.events_mut() {
+                        ev.kind = ev.kind.wrapping_mul(3);
+                    }
+                });
+            }
+        }
+        assert_eq!(events(&raw_track, &raw), events(&compressed_track, &compressed));
+    }
+
+    #[test]
+    fn modify_big_block() {
+        // Big enough that three varints per event overflow a u16 count
+        const N: usize = 22_000;
+        // Blocks this big get copied around on the stack
+        let test = std::thread::Builder::new().stack_size(64 << 20).spawn(|| {
+            let mut pool = BlockPool::<TraceEvent, N>::with_format(BlockFormat::Compressed);
+            let mut track = Track::default();
+            track.add_dummy_events(&mut pool, &Rng::with_seed(3), N);
+            pool.modify(track.block_locs[0], |block| {
+                for ev in block.events_mut() {
+                    ev.kind = 1;
+                }
+            });
+            assert_eq!(track.events(&pool).count(), N);
+            assert!(track.events(&pool).all(|ev| ev.kind == 1));
+        });
+        test.unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn unsorted_block() {
+        let mut pool: BlockPool = BlockPool::with_format(BlockFormat::Compressed);
+        let i = pool.alloc();
+        let ts = (0..16).map(|i| (i * 7919) % 1000 + PackedNs::MAX / 2).collect::<Vec<_>>();
+        for &ts in &ts {
+            pool.push(i, TraceEvent::try_new(1, ts, 5).unwrap());
+        }
+        assert!(pool.is_full(i));
+        assert_eq!(pool.block(i).events().map(|ev| ev.ts.unpack()).collect::<Vec<_>>(), ts);
+    }
+
+    #[test]
+    fn compressed_size() {
+        let trace = Trace::demo_trace_in(BlockPool::with_format(BlockFormat::Compressed), 4, 10_000);
+        let bytes_per_event = trace.pool.memory_usage() as f64 / 40_000.0;
+        assert!(bytes_per_event < 12.0, "{} bytes/event", bytes_per_event);
+    }
+}
@@ -0,0 +1,71 @@
+use crate::clock::AbsNs;
+use crate::trace::Ns;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A timestamp or duration doesn't fit in the 48 bits of a `PackedNs`
+    TimestampOverflow(u64),
+    /// An event started before the previous one on the same track
+    NonMonotonic { prev: Ns, ts: Ns },
+    /// The `BlockPool` has run out of `BlockIndex`es
+    BlockIndexExhausted,
+    /// An absolute time is before the trace's `TimeBase`
+    BeforeTimeBase { ts: AbsNs, base: AbsNs },
+    /// An absolute time is too far after the trace's `TimeBase` to store
+    SpanTooLarge { ts: AbsNs, base: AbsNs },
+    /// Reading or writing a file failed
+    Io(io::Error),
+    /// A file being imported is malformed
+    InvalidFormat(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::TimestampOverflow(ts) => write!(f, "time {}ns doesn't fit in 48 bits", ts),
+            Error::NonMonotonic { prev, ts } => write!(f, "event at {}ns comes after one at {}ns", ts, prev),
+            Error::BlockIndexExhausted => write!(f, "too many blocks for a 32 bit block index"),
+            Error::BeforeTimeBase { ts, base } => write!(f, "time {}ns is before the trace base of {}ns", ts, base),
+            Error::SpanTooLarge { ts, base } => {
+                write!(f, "time {}ns is more than 48 bits of nanoseconds after the trace base of {}ns", ts, base)
+            }
+            Error::Io(e) => write!(f, "{}", e),
+            Error::InvalidFormat(msg) => write!(f, "invalid trace: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// `io::Error`s can't be compared, so they're equal if they're the same kind.
+impl PartialEq for Error {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Error::TimestampOverflow(a), Error::TimestampOverflow(b)) => a == b,
+            (Error::NonMonotonic { prev: a, ts: b }, Error::NonMonotonic { prev: c, ts: d }) => (a, b) == (c, d),
+            (Error::BlockIndexExhausted, Error::BlockIndexExhausted) => true,
+            (Error::BeforeTimeBase { ts: a, base: b }, Error::BeforeTimeBase { ts: c, base: d }) => (a, b) == (c, d),
+            (Error::SpanTooLarge { ts: a, base: b }, Error::SpanTooLarge { ts: c, base: d }) => (a, b) == (c, d),
+            (Error::Io(a), Error::Io(b)) => a.kind() == b.kind(),
+            (Error::InvalidFormat(a), Error::InvalidFormat(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,163 @@
+use crate::analysis::walk_nested;
+use crate::kinds::KindRegistry;
+use crate::trace::{BlockPool, Ns, TraceEvent, Track};
+use crate::Trace;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::ops::Range;
+
+#[derive(Clone, Debug)]
+pub struct CallNode {
+    /// Meaningless for the root node
+    pub kind: u16,
+    pub count: usize,
+    pub total_time: Ns,
+    pub self_time: Ns,
+    pub children: Vec<usize>,
+}
+
+/// Slices merged by their path of kinds from the outermost enclosing slice,
+/// like the call trees of a sampling profiler.
+///
+/// A top-down tree has one node per distinct stack, with the total and self
+/// time of the slices at the end of it. A bottom-up tree starts from the
+/// innermost kind instead, and attributes self time to every node on the way
+/// out, so the roots are the kinds time is actually spent in.
+pub struct CallTree {
+    pub nodes: Vec<CallNode>,
+    bottom_up: bool,
+    child_lookup: HashMap<(usize, u16), usize>,
+}
+
+impl CallTree {
+    pub const ROOT: usize = 0;
+
+    pub fn top_down() -> Self {
+        Self::new(false)
+    }
+
+    pub fn bottom_up() -> Self {
+        Self::new(true)
+    }
+
+    fn new(bottom_up: bool) -> Self {
+        CallTree {
+            nodes: vec![CallNode { kind: 0, count: 0, total_time: 0, self_time: 0, children: vec![] }],
+            bottom_up,
+            child_lookup: HashMap::new(),
+        }
+    }
+
+    /// Builds a tree out of the slices starting inside `range` on each of the
+    /// chosen tracks of `trace`.
+    pub fn build(trace: &Trace, tracks: &[usize], range: Range<Ns>, bottom_up: bool) -> Self {
+        let mut tree = Self::new(bottom_up);
+        for &i in tracks {
+            tree.add_track(&trace.tracks[i].track, &trace.pool, range.clone());
+        }
+        tree
+    }
+
+    pub fn add_track<const N: usize>(&mut self, track: &Track<TraceEvent, N>, pool: &BlockPool<TraceEvent, N>, range: Range<Ns>) {
+        let mut path = vec![];
+        walk_nested(track, pool, range, |parents, ev, self_time| {
+            path.clear();
+            path.extend(parents.iter().map(|p| p.kind));
+            path.push(ev.kind);
+            if self.bottom_up {
+                path.reverse();
+                let mut node = Self::ROOT;
+                for (i, &kind) in path.iter().enumerate() {
+                    node = self.child(node, kind);
+                    let n = &mut self.nodes[node];
+                    n.total_time += self_time;
+                    if i == 0 {
+                        n.count += 1;
+                        n.self_time += self_time;
+                    }
+                }
+            } else {
+                let node = path.iter().fold(Self::ROOT, |node, &kind| self.child(node, kind));
+                let n = &mut self.nodes[node];
+                n.count += 1;
+                n.total_time += ev.dur.unpack();
+                n.self_time += self_time;
+            }
+            self.nodes[Self::ROOT].total_time += self_time;
+        });
+    }
+
+    fn child(&mut self, parent: usize, kind: u16) -> usize {
+        let nodes = &mut self.nodes;
+        *self.child_lookup.entry((parent, kind)).or_insert_with(|| {
+            nodes.push(CallNode { kind, count: 0, total_time: 0, self_time: 0, children: vec![] });
+            let i = nodes.len() - 1;
+            nodes[parent].children.push(i);
+            i
+        })
+    }
+
+    pub fn root(&self) -> &CallNode {
+        &self.nodes[Self::ROOT]
+    }
+
+    /// Renders the tree in the "folded stacks" format `flamegraph.pl` takes
+    /// as input: one line per stack with the self time in nanoseconds.
+    pub fn folded_stacks(&self, kinds: &KindRegistry) -> String {
+        fn visit(tree: &CallTree, kinds: &KindRegistry, node: usize, stack: &mut String, out: &mut String) {
+            let n = &tree.nodes[node];
+            let prefix_len = stack.len();
+            if node != CallTree::ROOT {
+                if prefix_len > 0 {
+                    stack.push(';');
+                }
+                // Semicolons separate frames, so keep them out of names
+                stack.push_str(&kinds.display(n.kind).replace(';', ":"));
+                // Bottom-up nodes only know the time of stacks ending there
+                // by what isn't passed on to their children
+                let weight = if tree.bottom_up {
+                    n.total_time - n.children.iter().map(|&c| tree.nodes[c].total_time).sum::<Ns>()
+                } else {
+                    n.self_time
+                };
+                if weight > 0 {
+                    writeln!(out, "{} {}", stack, weight).unwrap();
+                }
+            }
+            for &c in &n.children {
+                visit(tree, kinds, c, stack, out);
+            }
+            stack.truncate(prefix_len);
+        }
+
+        let mut out = String::new();
+        visit(self, kinds, Self::ROOT, &mut String::new(), &mut out);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::{PackedNs, TraceEvent};
+
+    #[test]
+    fn folded() {
+        let mut kinds = KindRegistry::new();
+        let (main, work, io) = (kinds.intern("main"), kinds.intern("work"), kinds.intern("io;read"));
+        let mut pool = BlockPool::new();
+        let mut track = Track::new();
+        for &(kind, ts, dur) in &[(main, 0, 100), (work, 10, 40), (io, 20, 10), (io, 60, 30), (work, 100, 20)] {
+            track.push(&mut pool, TraceEvent { kind, ts: PackedNs::new(ts), dur: PackedNs::new(dur) });
+        }
+
+        let mut tree = CallTree::top_down();
+        tree.add_track(&track, &pool, 0..1000);
+        assert_eq!(tree.root().total_time, 120);
+        assert_eq!(tree.folded_stacks(&kinds), "main 30\nmain;work 30\nmain;work;io:read 10\nmain;io:read 30\nwork 20\n");
+
+        let mut tree = CallTree::bottom_up();
+        tree.add_track(&track, &pool, 0..1000);
+        assert_eq!(tree.folded_stacks(&kinds), "io:read;work;main 10\nio:read;main 30\nwork 20\nwork;main 30\nmain 30\n");
+    }
+}
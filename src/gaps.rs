@@ -0,0 +1,132 @@
+use crate::iforest::IForestIndex;
+use crate::index::LargestGap;
+use crate::trace::{BlockPool, Event, Ns, Track};
+use std::ops::Range;
+
+struct GapSearch<'a, E: Event, const N: usize> {
+    pool: &'a BlockPool<E, N>,
+    track: &'a Track<E, N>,
+    index: &'a IForestIndex<LargestGap, E, N>,
+    range: Range<Ns>,
+    min_gap: Ns,
+    prev_end: Option<Ns>,
+    out: Vec<Range<Ns>>,
+}
+
+impl<'a, E: Event, const N: usize> GapSearch<'a, E, N> {
+    fn report(&mut self, next_start: Ns) {
+        if let Some(prev_end) = self.prev_end {
+            let gap = prev_end..next_start;
+            if gap.end >= gap.start + self.min_gap && gap.start < self.range.end && gap.end > self.range.start {
+                self.out.push(gap);
+            }
+        }
+    }
+
+    /// Visits blocks `lo..hi` in order, only descending into runs of blocks
+    /// which might contain a big enough gap.
+    fn visit(&mut self, lo: usize, hi: usize) {
+        let span = match self.index.range_query(lo..hi).0 {
+            Some(span) => span,
+            None => return,
+        };
+        if span.max_gap.unpack() < self.min_gap {
+            self.report(span.start.unpack());
+            self.prev_end = Some(self.prev_end.unwrap_or(0).max(span.end.unpack()));
+        } else if hi - lo == 1 {
+            for ev in self.pool.block(self.track.block_locs[lo]) {
+                let ts = ev.ts();
+                self.report(ts);
+                self.prev_end = Some(self.prev_end.unwrap_or(0).max(ts + ev.dur()));
+            }
+        } else {
+            let mid = lo + (hi - lo) / 2;
+            self.visit(lo, mid);
+            self.visit(mid, hi);
+        }
+    }
+}
+
+/// Finds stretches of at least `min_gap` not covered by any event on `track`
+/// which overlap `range`. Gaps are returned whole rather than clipped to the
+/// range, and the time before the first event or after the last one doesn't
+/// count as a gap.
+///
+/// Uses `index` to skip over runs of blocks where events are too dense to
+/// have any gaps that big, so is cheap for sparse results on huge tracks.
+pub fn find_gaps<E: Event, const N: usize>(
+    pool: &BlockPool<E, N>,
+    track: &Track<E, N>,
+    index: &IForestIndex<LargestGap, E, N>,
+    range: Range<Ns>,
+    min_gap: Ns,
+) -> Vec<Range<Ns>> {
+    let lo = track.block_containing(pool, range.start);
+    // Include the block after the range so we see where a gap in it ends
+    let hi = track.block_locs
+        .partition_point(|i| pool.start_time(*i) < range.end)
+        .saturating_add(1)
+        .min(track.block_locs.len());
+    let mut search = GapSearch {
+        pool,
+        track,
+        index,
+        range,
+        min_gap: min_gap.max(1),
+        // Events before the range can still cover the start of it
+        prev_end: index.range_query(0..lo).0.map(|span| span.end.unpack()),
+        out: vec![],
+    };
+    if lo < hi {
+        search.visit(lo, hi);
+    }
+    search.out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::TrackIndex;
+    use crate::trace::{PackedNs, TraceEvent};
+    use fastrand::Rng;
+
+    #[test]
+    fn prop_test_find_gaps() {
+        let mut pool = BlockPool::new();
+        let mut track = Track::new();
+        let rng = Rng::new();
+        track.add_dummy_events(&mut pool, &rng, 2000);
+        // A long event covering some of the gaps after it
+        let end = track.after_last_time(&pool).unwrap();
+        track.push(&mut pool, TraceEvent { kind: 0, ts: PackedNs::new(end + 50_000), dur: PackedNs::new(200_000) });
+        let mut ts = end + 250_000;
+        for _ in 0..100 {
+            ts += rng.u64(..20_000);
+            track.push(&mut pool, TraceEvent { kind: 0, ts: PackedNs::new(ts), dur: PackedNs::new(rng.u64(..5_000)) });
+        }
+        let index = IForestIndex::<LargestGap>::build(&track, &pool);
+
+        let time_bounds = 0..=track.after_last_time(&pool).unwrap();
+        for _ in 0..1000 {
+            let t1 = rng.u64(time_bounds.clone());
+            let t2 = rng.u64(time_bounds.clone());
+            let range = t1.min(t2)..t1.max(t2);
+            let min_gap = rng.u64(1..15_000);
+
+            let mut correct = vec![];
+            let mut prev_end: Option<Ns> = None;
+            for ev in track.events(&pool) {
+                let ts = ev.ts.unpack();
+                if let Some(p) = prev_end {
+                    if ts >= p + min_gap && p < range.end && ts > range.start {
+                        correct.push(p..ts);
+                    }
+                }
+                prev_end = Some(prev_end.unwrap_or(0).max(ts + ev.dur.unpack()));
+            }
+
+            let res = find_gaps(&pool, &track, &index, range.clone(), min_gap);
+            assert_eq!(res, correct, "failed for {:?} - {}", range, min_gap);
+        }
+    }
+}
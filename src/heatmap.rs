@@ -0,0 +1,113 @@
+use crate::index::{BusyTime, LongestEvent};
+use crate::trace::Ns;
+use crate::{aggregate_by_steps, Trace, TrackInfo};
+use std::ops::Range;
+use std::thread;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum HeatmapMetric {
+    EventCount,
+    /// Fraction of the bucket covered by events, from 0 to 1
+    BusyFraction,
+    /// Duration of the longest event starting in the bucket
+    LongestEvent,
+}
+
+/// A dense tracks × buckets matrix of one metric, stored row by row.
+pub struct Heatmap {
+    pub time_span: Range<Ns>,
+    pub time_step: u64,
+    pub buckets: usize,
+    pub values: Vec<f64>,
+}
+
+impl Heatmap {
+    pub fn tracks(&self) -> usize {
+        self.values.len().checked_div(self.buckets).unwrap_or(0)
+    }
+
+    pub fn row(&self, track: usize) -> &[f64] {
+        &self.values[track * self.buckets..(track + 1) * self.buckets]
+    }
+
+    pub fn get(&self, track: usize, bucket: usize) -> f64 {
+        self.row(track)[bucket]
+    }
+}
+
+fn fill_row(trace: &Trace, track: &TrackInfo, metric: HeatmapMetric, time_span: Range<Ns>, time_step: u64, row: &mut [f64]) {
+    let locs = &track.track.block_locs;
+    match metric {
+        HeatmapMetric::EventCount => {
+            let res = track.event_counts(&trace.pool, time_span, time_step);
+            for (out, count) in row.iter_mut().zip(res) {
+                *out = count as f64;
+            }
+        }
+        HeatmapMetric::BusyFraction => {
+            let res = aggregate_by_steps(&trace.pool, locs, &track.busy_index, time_span.clone(), time_step);
+            row.copy_from_slice(&BusyTime::fractions(&res, time_span, time_step));
+        }
+        HeatmapMetric::LongestEvent => {
+            let res = aggregate_by_steps(&trace.pool, locs, &track.zoom_index, time_span, time_step);
+            for (out, LongestEvent(ev)) in row.iter_mut().zip(res.iter().skip(1)) {
+                *out = ev.map_or(0.0, |ev| ev.dur.unpack() as f64);
+            }
+        }
+    }
+}
+
+impl Trace {
+    /// Computes `metric` for every track and every step-sized bucket of
+    /// `time_span`, splitting the tracks across threads. Buckets past the end
+    /// of a track are zero.
+    pub fn heatmap(&self, time_span: Range<Ns>, time_step: u64, metric: HeatmapMetric) -> Heatmap {
+        let tracks = (0..self.tracks.len()).collect::<Vec<_>>();
+        self.heatmap_of(&tracks, time_span, time_step, metric)
+    }
+
+    /// Like `heatmap` but with a row for each of `tracks`, in that order.
+    pub fn heatmap_of(&self, tracks: &[usize], time_span: Range<Ns>, time_step: u64, metric: HeatmapMetric) -> Heatmap {
+        let buckets = (time_span.end - time_span.start).div_ceil(time_step) as usize;
+        let mut values = vec![0.0; buckets * tracks.len()];
+        if buckets > 0 && !tracks.is_empty() {
+            let threads = thread::available_parallelism().map_or(1, |n| n.get());
+            let tracks_per_thread = tracks.len().div_ceil(threads);
+            thread::scope(|s| {
+                for (tracks, rows) in tracks.chunks(tracks_per_thread).zip(values.chunks_mut(tracks_per_thread * buckets)) {
+                    let time_span = time_span.clone();
+                    s.spawn(move || {
+                        for (&i, row) in tracks.iter().zip(rows.chunks_mut(buckets)) {
+                            fill_row(self, &self.tracks[i], metric, time_span.clone(), time_step, row);
+                        }
+                    });
+                }
+            });
+        }
+        Heatmap { time_span, time_step, buckets, values }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heatmap_matches_per_track() {
+        let trace = Trace::demo_trace(7, 1000);
+        let bounds = trace.time_bounds().unwrap();
+        let step = (bounds.end - bounds.start) / 37;
+        let counts = trace.heatmap(bounds.clone(), step, HeatmapMetric::EventCount);
+        assert_eq!(counts.tracks(), 7);
+        assert_eq!(counts.buckets as u64, (bounds.end - bounds.start).div_ceil(step));
+        for (i, track) in trace.tracks.iter().enumerate() {
+            let total: f64 = counts.row(i).iter().sum();
+            let before = track.track.events(&trace.pool).filter(|ev| ev.ts.unpack() < bounds.start).count();
+            assert_eq!(total as usize + before, 1000);
+        }
+
+        let busy = trace.heatmap(bounds.clone(), step, HeatmapMetric::BusyFraction);
+        assert_eq!(busy.row(3), &trace.tracks[3].busy_fractions(&trace.pool, bounds, step)[..]);
+        assert!(busy.values.iter().all(|f| (0.0..=1.0).contains(f)));
+    }
+}
@@ -0,0 +1,102 @@
+use crate::trace::{BlockPool, Ns, TraceEvent};
+use crate::TrackInfo;
+use std::ops::Range;
+
+/// An event found by `TrackInfo::event_at`, with its index in the track for
+/// looking up its `args`.
+#[derive(Copy, Clone)]
+pub struct HitEvent {
+    pub index: usize,
+    pub event: TraceEvent,
+}
+
+impl TrackInfo {
+    /// The event under time `t`, or failing that the last one to start
+    /// within `slop` of it, for picking events too short to point at. Where
+    /// events are nested, the innermost is picked.
+    ///
+    /// This finds the real event even when the view only drew a bucket
+    /// standing in for it, by descending the zoom index to the leaf blocks
+    /// that could hold it.
+    pub fn event_at(&self, pool: &BlockPool, t: Ns, slop: Ns) -> Option<HitEvent> {
+        self.last_overlapping(pool, t, t).or_else(|| {
+            if slop == 0 {
+                return None;
+            }
+            self.last_overlapping(pool, t.saturating_sub(slop), t.saturating_add(slop))
+        })
+    }
+
+    /// The last event to start which overlaps `lo..=hi`, counting instants
+    /// as a nanosecond long.
+    fn last_overlapping(&self, pool: &BlockPool, lo: Ns, hi: Ns) -> Option<HitEvent> {
+        // Later blocks only have events starting after `hi`
+        let end = self.track.block_locs.partition_point(|&b| pool.start_time(b) <= hi);
+        self.search_blocks(pool, 0..end, lo, hi)
+    }
+
+    /// Tries later blocks first, skipping runs of blocks where even the
+    /// longest event couldn't reach `lo` if it started as late as possible.
+    fn search_blocks(&self, pool: &BlockPool, blocks: Range<usize>, lo: Ns, hi: Ns) -> Option<HitEvent> {
+        if blocks.is_empty() {
+            return None;
+        }
+        let locs = &self.track.block_locs;
+        // Events in the range start no later than the next block does
+        let latest_start = locs.get(blocks.end).map_or(hi, |&b| pool.start_time(b)).min(hi);
+        let longest = self.zoom_index.range_query(blocks.clone()).0.map_or(0, |ev| ev.dur.unpack());
+        if latest_start + longest.max(1) <= lo {
+            return None;
+        }
+        if blocks.len() == 1 {
+            let block = pool.block(locs[blocks.start]);
+            let i = (0..block.len()).rev().find(|&i| {
+                let (ts, dur) = (block.ts(i), block.dur(i));
+                ts <= hi && ts + dur.max(1) > lo
+            })?;
+            let index = self.block_starts[blocks.start] + i;
+            return Some(HitEvent { index, event: block.event(i) });
+        }
+        let mid = blocks.start + blocks.len() / 2;
+        self.search_blocks(pool, mid..blocks.end, lo, hi)
+            .or_else(|| self.search_blocks(pool, blocks.start..mid, lo, hi))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::trace::{PackedNs, Track, TraceEvent};
+    use crate::{Trace, TrackInfo};
+    use fastrand::Rng;
+
+    #[test]
+    fn prop_test_event_at() {
+        let mut trace = Trace::new();
+        let rng = Rng::with_seed(4);
+        let mut track = Track::new();
+        track.add_dummy_events(&mut trace.pool, &rng, 2000);
+        // A long event early on, and some instants
+        track.push(&mut trace.pool, TraceEvent::try_new(0, 100_000_000, 0).unwrap());
+        let mut events = track.events(&trace.pool).collect::<Vec<_>>();
+        events[3].dur = PackedNs::new(15_000_000);
+        track.rewrite(&mut trace.pool, &events).unwrap();
+        let info = TrackInfo::new(track, &trace.pool);
+
+        let brute = |lo: u64, hi: u64| events.iter().enumerate().rev()
+            .find(|(_, ev)| ev.ts.unpack() <= hi && ev.ts.unpack() + ev.dur.unpack().max(1) > lo)
+            .map(|(i, _)| i);
+        for _ in 0..2000 {
+            let t = rng.u64(0..101_000_000);
+            let slop = [0, 10, 5000][rng.usize(0..3)];
+            let expected = brute(t, t).or_else(|| if slop == 0 { None } else { brute(t.saturating_sub(slop), t + slop) });
+            let hit = info.event_at(&trace.pool, t, slop);
+            assert_eq!(hit.map(|h| h.index), expected, "at {} +- {}", t, slop);
+            if let Some(hit) = hit {
+                assert_eq!(hit.event.ts.unpack(), events[hit.index].ts.unpack());
+            }
+        }
+        // The instant at the end
+        assert_eq!(info.event_at(&trace.pool, 100_000_000, 0).map(|h| h.index), Some(2000));
+        assert!(TrackInfo::new(Track::new(), &trace.pool).event_at(&trace.pool, 5, 5).is_none());
+    }
+}
@@ -1,9 +1,13 @@
-use crate::trace::{TraceBlock, BlockPool, Track};
+use crate::trace::{BlockRef, BlockPool, Event, Track, TraceEvent, EVENTS_PER_BLOCK};
+use std::marker::PhantomData;
+use std::mem;
 use crate::index::{Aggregate, TrackIndex};
 use std::ops::Range;
 
-pub struct IForestIndex<A: Aggregate> {
+/// Indexes a track of `E`s with blocks of `N` events, one leaf per block.
+pub struct IForestIndex<A: Aggregate<E>, E: Event = TraceEvent, const N: usize = EVENTS_PER_BLOCK> {
     pub vals: Vec<A>,
+    _event: PhantomData<E>,
 }
 
 //                #
@@ -13,10 +17,18 @@ pub struct IForestIndex<A: Aggregate> {
 // 0|1|2|3|4|5|6|7|8|9|
 impl<A: Aggregate> IForestIndex<A> {
     pub fn new() -> Self {
-        IForestIndex { vals: vec![] }
+        Self::default()
     }
+}
+
+impl<A: Aggregate<E>, E: Event, const N: usize> Default for IForestIndex<A, E, N> {
+    fn default() -> Self {
+        IForestIndex { vals: vec![], _event: PhantomData }
+    }
+}
 
-    pub fn push(&mut self, block: &TraceBlock) {
+impl<A: Aggregate<E>, E: Event, const N: usize> IForestIndex<A, E, N> {
+    pub fn push(&mut self, block: &BlockRef<'_, E, N>) {
         self.vals.push(A::from_block(block));
 
         let len = self.vals.len();
@@ -74,13 +86,17 @@ impl<A: Aggregate> IForestIndex<A> {
         }
         combined
     }
+
+    pub fn memory_usage(&self) -> usize {
+        self.vals.capacity() * mem::size_of::<A>()
+    }
 }
 
-impl<A: Aggregate> TrackIndex<A> for IForestIndex<A> {
-    fn build(track: &Track, pool: &BlockPool) -> IForestIndex<A> {
-        let mut forest = IForestIndex::new();
+impl<A: Aggregate<E>, E: Event, const N: usize> TrackIndex<A, E, N> for IForestIndex<A, E, N> {
+    fn build(track: &Track<E, N>, pool: &BlockPool<E, N>) -> IForestIndex<A, E, N> {
+        let mut forest = IForestIndex::default();
         for i in &track.block_locs {
-            forest.push(&pool.blocks[*i as usize]);
+            forest.push(&pool.block(*i));
         }
         // TODO in parallel
         forest
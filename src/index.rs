@@ -1,21 +1,31 @@
-use crate::trace::{TraceBlock, TraceEvent, Track, BlockPool};
+use crate::trace::{BlockRef, Event, TraceEvent, Track, BlockPool, PackedNs, Ns, EVENTS_PER_BLOCK};
+use std::ops::Range;
 
-pub trait Aggregate: Clone {
+/// A summary of a run of events of type `E`. Aggregates which only use the
+/// `Event` accessors can implement this for every event type.
+pub trait Aggregate<E: Event = TraceEvent>: Clone {
     fn empty() -> Self;
-    fn from_event(ev: &TraceEvent) -> Self;
+    fn from_event(ev: &E) -> Self;
     fn combine(&self, other: &Self) -> Self;
 
-    fn from_block(block: &TraceBlock) -> Self {
+    /// Aggregates events `r` of a block. Override this for aggregates
+    /// which only need some fields and can read them straight from the
+    /// block, which is faster for `BlockFormat::Soa` pools.
+    fn from_events<const N: usize>(block: &BlockRef<'_, E, N>, r: Range<usize>) -> Self {
         let mut c = Self::empty();
-        for ev in block.events() {
-            c = Self::combine(&c, &Self::from_event(ev));
+        for i in r {
+            c = Self::combine(&c, &Self::from_event(&block.event(i)));
         }
         c
     }
+
+    fn from_block<const N: usize>(block: &BlockRef<'_, E, N>) -> Self {
+        Self::from_events(block, 0..block.len())
+    }
 }
 
-pub trait TrackIndex<A: Aggregate> {
-    fn build(track: &Track, pool: &BlockPool) -> Self;
+pub trait TrackIndex<A: Aggregate<E>, E: Event = TraceEvent, const N: usize = EVENTS_PER_BLOCK> {
+    fn build(track: &Track<E, N>, pool: &BlockPool<E, N>) -> Self;
 }
 
 // === Concrete aggregations
@@ -39,8 +49,8 @@ impl Aggregate for LongestEvent {
             .map(|x| x.clone()))
     }
 
-    fn from_block(block: &TraceBlock) -> Self {
-        LongestEvent(block.events().iter().max_by_key(|ev| ev.dur.unpack()).map(|x| x.clone()))
+    fn from_events<const N: usize>(block: &BlockRef<'_, TraceEvent, N>, r: Range<usize>) -> Self {
+        LongestEvent(block.longest_in(r).map(|i| block.event(i)))
     }
 }
 
@@ -71,34 +81,147 @@ impl Aggregate for LongestEvent {
 //     }
 // }
 
+#[derive(Copy, Clone)]
+pub struct GapSpan {
+    pub start: PackedNs,
+    /// End of the latest ending event, which isn't necessarily the last one
+    pub end: PackedNs,
+    pub max_gap: PackedNs,
+}
+
+/// Where a run of events starts and ends, and the largest stretch inside it
+/// not covered by any event. Combining only ever overestimates `max_gap`,
+/// since a long event on the left can cover gaps on the right, so it's
+/// useful for skipping runs without big enough gaps.
+#[derive(Clone)]
+pub struct LargestGap(pub Option<GapSpan>);
+
+impl<E: Event> Aggregate<E> for LargestGap {
+    fn empty() -> Self {
+        LargestGap(None)
+    }
+
+    fn from_event(ev: &E) -> Self {
+        LargestGap(Some(GapSpan {
+            start: PackedNs::new(ev.ts()),
+            end: PackedNs::new(ev.ts() + ev.dur()),
+            max_gap: PackedNs::new(0),
+        }))
+    }
+
+    fn combine(&self, other: &Self) -> Self {
+        match (self.0, other.0) {
+            (None, x) | (x, None) => LargestGap(x),
+            (Some(a), Some(b)) => {
+                let gap = b.start.unpack().saturating_sub(a.end.unpack());
+                let max_gap = a.max_gap.unpack().max(b.max_gap.unpack()).max(gap);
+                LargestGap(Some(GapSpan {
+                    start: a.start,
+                    end: PackedNs::new(a.end.unpack().max(b.end.unpack())),
+                    max_gap: PackedNs::new(max_gap),
+                }))
+            }
+        }
+    }
+}
+
+/// How much time a run of events covers, counting overlapping time once.
+///
+/// Overlapping events are assumed to nest, which makes this exact for tracks
+/// of non-overlapping or properly nested slices, except that when a run
+/// starts inside the last slice of the run before it, any gaps in it before
+/// that slice ends get counted as covered.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct BusyTime {
+    pub start: Ns,
+    /// End of the latest ending event
+    pub end: Ns,
+    pub busy: Ns,
+}
+
+impl<E: Event> Aggregate<E> for BusyTime {
+    fn empty() -> Self {
+        BusyTime { start: Ns::MAX, end: 0, busy: 0 }
+    }
+
+    fn from_event(ev: &E) -> Self {
+        let start = ev.ts();
+        let dur = ev.dur();
+        BusyTime { start, end: start + dur, busy: dur }
+    }
+
+    fn combine(&self, other: &Self) -> Self {
+        let overlap = self.end.saturating_sub(other.start).min(other.busy);
+        BusyTime {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+            busy: self.busy + other.busy - overlap,
+        }
+    }
+}
+
+impl BusyTime {
+    /// Turns the result of `aggregate_by_steps` into the fraction of each
+    /// step-sized bucket of `time_span` covered by events.
+    ///
+    /// `aggregate_by_steps` puts each event in the bucket it starts in, so
+    /// time covered past the end of a bucket is carried over into the
+    /// following ones, including past the end of `buckets` if the track
+    /// ended early. The first element of `buckets`, holding the events before
+    /// the span, only contributes what it carries over.
+    pub fn fractions(buckets: &[BusyTime], time_span: Range<Ns>, time_step: u64) -> Vec<f64> {
+        let n = (time_span.end - time_span.start).div_ceil(time_step);
+        let mut carry_end = buckets.first().map_or(0, |b| b.end);
+        let mut out = Vec::with_capacity(n as usize);
+        let empty = <BusyTime as Aggregate>::empty();
+        for i in 0..n {
+            let b = buckets.get(i as usize + 1).unwrap_or(&empty);
+            let lo = time_span.start + i * time_step;
+            let hi = lo.saturating_add(time_step);
+            let carried = BusyTime { start: lo, end: carry_end.max(lo), busy: carry_end.saturating_sub(lo) };
+            let covered = <BusyTime as Aggregate>::combine(&carried, b);
+            // Whatever ends last started before `hi`, so covers all the way
+            let spill = covered.end.saturating_sub(hi);
+            let inside = covered.busy.saturating_sub(spill).min(time_step);
+            out.push(inside as f64 / time_step as f64);
+            carry_end = covered.end;
+        }
+        out
+    }
+}
+
 /// For debugging
 #[derive(Clone)]
 pub struct EventCount(pub usize);
 
-impl Aggregate for EventCount {
+impl<E: Event> Aggregate<E> for EventCount {
     fn empty() -> Self {
         EventCount(0)
     }
 
-    fn from_event(_ev: &TraceEvent) -> Self {
+    fn from_event(_ev: &E) -> Self {
         EventCount(1)
     }
 
     fn combine(&self, other: &Self) -> Self {
         EventCount(self.0 + other.0)
     }
+
+    fn from_events<const N: usize>(_block: &BlockRef<'_, E, N>, r: Range<usize>) -> Self {
+        EventCount(r.len())
+    }
 }
 
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub struct TsSum(pub u64);
 
-impl Aggregate for TsSum {
+impl<E: Event> Aggregate<E> for TsSum {
     fn empty() -> Self {
         Self(0)
     }
 
-    fn from_event(ev: &TraceEvent) -> Self {
-        Self(ev.ts.unpack())
+    fn from_event(ev: &E) -> Self {
+        Self(ev.ts())
     }
 
     fn combine(&self, other: &Self) -> Self {
@@ -0,0 +1,150 @@
+use crate::error::Result;
+use crate::iforest::IForestIndex;
+use crate::index::{Aggregate, TrackIndex};
+use crate::meta::TrackMeta;
+use crate::trace::{BlockPool, BlockRef, Event, Ns, PackableEvent, PackedNs, TraceEvent, Track};
+use crate::{aggregate_by_steps, Trace};
+use std::ops::Range;
+
+/// A marker at a point in time with no duration, like a vsync, the start of
+/// a GC or a log line.
+#[derive(Copy, Clone)]
+pub struct InstantEvent {
+    pub kind: u16,
+    pub ts: PackedNs,
+}
+
+impl InstantEvent {
+    pub fn new(kind: u16, ts: Ns) -> Result<Self> {
+        Ok(InstantEvent { kind, ts: PackedNs::try_new(ts)? })
+    }
+}
+
+impl Event for InstantEvent {
+    const NULL: Self = InstantEvent { kind: 0, ts: PackedNs::new(0) };
+
+    #[inline]
+    fn ts(&self) -> Ns {
+        self.ts.unpack()
+    }
+}
+
+impl PackableEvent for InstantEvent {
+    fn to_trace_event(&self) -> TraceEvent {
+        TraceEvent { kind: self.kind, ts: self.ts, dur: PackedNs::new(0) }
+    }
+
+    fn from_trace_event(ev: TraceEvent) -> Self {
+        InstantEvent { kind: ev.kind, ts: ev.ts }
+    }
+}
+
+/// How many instants there are in a run and the first and last of them,
+/// which is enough to draw a tick for a lone instant or a range with a
+/// count for a cluster of them.
+#[derive(Copy, Clone)]
+pub struct InstantSummary {
+    pub count: usize,
+    pub first: Option<InstantEvent>,
+    pub last: Option<InstantEvent>,
+}
+
+impl Aggregate<InstantEvent> for InstantSummary {
+    fn empty() -> Self {
+        InstantSummary { count: 0, first: None, last: None }
+    }
+
+    fn from_event(ev: &InstantEvent) -> Self {
+        InstantSummary { count: 1, first: Some(*ev), last: Some(*ev) }
+    }
+
+    fn combine(&self, other: &Self) -> Self {
+        InstantSummary {
+            count: self.count + other.count,
+            first: self.first.or(other.first),
+            last: other.last.or(self.last),
+        }
+    }
+
+    fn from_events<const N: usize>(block: &BlockRef<'_, InstantEvent, N>, r: Range<usize>) -> Self {
+        if r.is_empty() {
+            return Self::empty();
+        }
+        InstantSummary {
+            count: r.len(),
+            first: Some(block.event(r.start)),
+            last: Some(block.event(r.end - 1)),
+        }
+    }
+}
+
+pub struct InstantTrackInfo {
+    pub meta: TrackMeta,
+    pub track: Track<InstantEvent>,
+    pub index: IForestIndex<InstantSummary, InstantEvent>,
+}
+
+impl InstantTrackInfo {
+    pub fn new(track: Track<InstantEvent>, pool: &BlockPool<InstantEvent>) -> Self {
+        InstantTrackInfo {
+            meta: TrackMeta::default(),
+            index: IForestIndex::build(&track, pool),
+            track,
+        }
+    }
+
+    pub fn with_meta(mut self, meta: TrackMeta) -> Self {
+        self.meta = meta;
+        self
+    }
+
+    /// For after the events of the track have been modified in place.
+    pub fn rebuild_index(&mut self, pool: &BlockPool<InstantEvent>) {
+        self.index = IForestIndex::build(&self.track, pool);
+    }
+
+    /// Summaries of the instants in each step of `time_span`, bucketed like
+    /// `aggregate_by_steps`.
+    pub fn summaries(&self, pool: &BlockPool<InstantEvent>, time_span: Range<Ns>, time_step: u64) -> Vec<InstantSummary> {
+        aggregate_by_steps(pool, &self.track.block_locs, &self.index, time_span, time_step)
+    }
+}
+
+impl Trace {
+    /// Adds a track of instants, which must be sorted, returning its index
+    /// in `instant_tracks`.
+    pub fn add_instant_track(&mut self, meta: TrackMeta, events: impl IntoIterator<Item = InstantEvent>) -> Result<usize> {
+        let mut track = Track::default();
+        for ev in events {
+            track.try_push(&mut self.instant_pool, ev)?;
+        }
+        self.instant_tracks.push(InstantTrackInfo::new(track, &self.instant_pool).with_meta(meta));
+        Ok(self.instant_tracks.len() - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instant_summaries() {
+        let mut trace = Trace::new();
+        let times = (0..1000u64).map(|i| i * i).collect::<Vec<_>>();
+        let events = times.iter().map(|&ts| InstantEvent::new((ts % 7) as u16, ts).unwrap());
+        let i = trace.add_instant_track(TrackMeta { name: "vsync".into(), ..Default::default() }, events).unwrap();
+        assert_eq!(trace.time_bounds(), Some(0..999 * 999));
+
+        let span = 10_000..500_000;
+        let step = 7_000;
+        let sums = trace.instant_tracks[i].summaries(&trace.instant_pool, span.clone(), step);
+        for (b, sum) in sums.iter().enumerate() {
+            let lo = if b == 0 { 0 } else { span.start + (b as u64 - 1) * step };
+            let hi = span.start + b as u64 * step;
+            let inside = times.iter().copied().filter(|&t| t >= lo && t < hi).collect::<Vec<_>>();
+            assert_eq!(sum.count, inside.len());
+            assert_eq!(sum.first.map(|ev| ev.ts.unpack()), inside.first().copied());
+            assert_eq!(sum.last.map(|ev| ev.ts.unpack()), inside.last().copied());
+        }
+    }
+}
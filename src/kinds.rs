@@ -0,0 +1,88 @@
+use crate::error::{Error, Result};
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::collections::HashMap;
+
+/// Maps the `kind` stored in each `TraceEvent` to a name. Importers intern
+/// names as they go, events that were pushed with a kind that was never
+/// registered just display as a number.
+#[derive(Clone)]
+pub struct KindRegistry {
+    names: Vec<String>,
+    ids: HashMap<String, u16>,
+}
+
+impl KindRegistry {
+    pub fn new() -> Self {
+        KindRegistry {
+            names: vec![],
+            ids: HashMap::new(),
+        }
+    }
+
+    /// Returns the kind for `name`, registering it if it's new. Fails if
+    /// there are already 2^16 distinct names.
+    pub fn try_intern(&mut self, name: &str) -> Result<u16> {
+        if let Some(&kind) = self.ids.get(name) {
+            return Ok(kind);
+        }
+        let kind = u16::try_from(self.names.len())
+            .map_err(|_| Error::InvalidFormat("more than 65536 event kinds".to_owned()))?;
+        self.names.push(name.to_owned());
+        self.ids.insert(name.to_owned(), kind);
+        Ok(kind)
+    }
+
+    /// Like `try_intern`, for a known small set of names.
+    ///
+    /// Panics if more than 2^16 distinct names are registered.
+    pub fn intern(&mut self, name: &str) -> u16 {
+        self.try_intern(name).expect("too many event kinds")
+    }
+
+    pub fn get(&self, name: &str) -> Option<u16> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn name(&self, kind: u16) -> Option<&str> {
+        self.names.get(kind as usize).map(|s| s.as_str())
+    }
+
+    /// Name to show for `kind`, falling back to the number.
+    pub fn display(&self, kind: u16) -> Cow<'_, str> {
+        match self.name(kind) {
+            Some(name) => Cow::Borrowed(name),
+            None => Cow::Owned(format!("kind {}", kind)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+impl Default for KindRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn too_many_kinds() {
+        let mut kinds = KindRegistry::new();
+        for i in 0..=u16::MAX as usize {
+            assert_eq!(kinds.try_intern(&i.to_string()), Ok(i as u16));
+        }
+        assert_eq!(kinds.try_intern("0"), Ok(0));
+        assert!(matches!(kinds.try_intern("new"), Err(Error::InvalidFormat(_))));
+        assert_eq!(kinds.len(), 1 << 16);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::error::Result;
+use crate::meta::TrackMeta;
+use crate::trace::{Ns, TraceEvent, Track};
+use crate::{Trace, TrackInfo};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::ops::Range;
+
+/// Assigns each span a lane such that spans in the same lane don't overlap,
+/// using as few lanes as possible. `spans` is sorted by start time first,
+/// and the result gives the lane of each span in the sorted order.
+///
+/// Spans go in the lowest numbered lane that's free when they start, which
+/// needs exactly as many lanes as the most spans open at once, and keeps
+/// the busiest lanes at the top.
+pub fn allocate_lanes(spans: &mut [TraceEvent]) -> Vec<u32> {
+    spans.sort_by_key(|ev| ev.ts.unpack());
+    // Lanes in use by when they free up, and lanes which are free
+    let mut busy: BinaryHeap<Reverse<(Ns, u32)>> = BinaryHeap::new();
+    let mut free: BinaryHeap<Reverse<u32>> = BinaryHeap::new();
+    let mut lanes = 0;
+    let mut out = Vec::with_capacity(spans.len());
+    for ev in spans.iter() {
+        let ts = ev.ts.unpack();
+        while let Some(&Reverse((end, lane))) = busy.peek() {
+            if end > ts {
+                break;
+            }
+            busy.pop();
+            free.push(Reverse(lane));
+        }
+        let lane = match free.pop() {
+            Some(Reverse(lane)) => lane,
+            None => {
+                lanes += 1;
+                lanes - 1
+            }
+        };
+        busy.push(Reverse((ts + ev.dur.unpack(), lane)));
+        out.push(lane);
+    }
+    out
+}
+
+impl Trace {
+    /// Adds spans which can overlap arbitrarily, like network requests, as
+    /// a group of tracks with one per lane from `allocate_lanes`. Each track
+    /// gets a copy of `meta` with `TrackMeta::lane` set, so they sort
+    /// together and can be drawn as stacked rows. Returns the indices of
+    /// the new tracks.
+    pub fn add_async_track(&mut self, meta: TrackMeta, mut spans: Vec<TraceEvent>) -> Result<Range<usize>> {
+        let lanes = allocate_lanes(&mut spans);
+        let lane_count = lanes.iter().max().map_or(0, |&l| l as usize + 1);
+        let mut tracks = (0..lane_count).map(|_| Track::new()).collect::<Vec<_>>();
+        for (ev, &lane) in spans.iter().zip(&lanes) {
+            tracks[lane as usize].try_push(&mut self.pool, *ev)?;
+        }
+        let first = self.tracks.len();
+        for (lane, track) in tracks.into_iter().enumerate() {
+            let meta = TrackMeta { lane: Some(lane as u32), ..meta.clone() };
+            self.tracks.push(TrackInfo::new(track, &self.pool).with_meta(meta));
+        }
+        Ok(first..self.tracks.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fastrand::Rng;
+
+    #[test]
+    fn prop_test_lanes() {
+        let rng = Rng::with_seed(9);
+        for _ in 0..100 {
+            let mut spans = (0..rng.usize(0..200))
+                .map(|_| TraceEvent::try_new(0, rng.u64(0..100_000), rng.u64(0..20_000)).unwrap())
+                .collect::<Vec<_>>();
+            let lanes = allocate_lanes(&mut spans);
+
+            let mut lane_end = vec![];
+            for (ev, &lane) in spans.iter().zip(&lanes) {
+                let lane = lane as usize;
+                if lane_end.len() <= lane {
+                    lane_end.resize(lane + 1, 0);
+                }
+                assert!(lane_end[lane] <= ev.ts.unpack(), "overlap in lane {}", lane);
+                lane_end[lane] = ev.ts.unpack() + ev.dur.unpack();
+            }
+
+            // The most spans open at once, ends sorting before starts
+            let mut edges = spans.iter()
+                .flat_map(|ev| vec![(ev.ts.unpack(), 1), (ev.ts.unpack() + ev.dur.unpack(), -1)])
+                .collect::<Vec<(u64, i32)>>();
+            edges.sort();
+            let max_open = edges.iter().scan(0, |open, e| { *open += e.1; Some(*open) }).max().unwrap_or(0);
+            assert_eq!(lane_end.len(), max_open as usize);
+        }
+    }
+
+    #[test]
+    fn async_track() {
+        let mut trace = Trace::new();
+        let spans = [(0, 100), (10, 20), (50, 100), (120, 5)]
+            .iter()
+            .map(|&(ts, dur)| TraceEvent::try_new(0, ts, dur).unwrap())
+            .collect();
+        let meta = TrackMeta { name: "requests".into(), pid: Some(1), ..Default::default() };
+        let tracks = trace.add_async_track(meta, spans).unwrap();
+        assert_eq!(tracks, 0..2);
+        let starts = |i: usize| trace.tracks[i].track.events(&trace.pool).map(|ev| ev.ts.unpack()).collect::<Vec<_>>();
+        assert_eq!(starts(0), vec![0, 120]);
+        assert_eq!(starts(1), vec![10, 50]);
+        assert_eq!(trace.processes()[0].tracks, vec![0, 1]);
+        assert_eq!(trace.tracks[1].meta.lane, Some(1));
+    }
+}
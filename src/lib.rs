@@ -1,19 +1,56 @@
+pub mod analysis;
+pub mod args;
+pub mod cache;
+pub mod chrome;
+pub mod clock;
+pub mod compress;
+pub mod error;
+pub mod flame;
+pub mod gaps;
+pub mod heatmap;
+pub mod hit;
 pub mod iforest;
+pub mod json;
 pub mod index;
+pub mod instant;
+pub mod kinds;
+pub mod lanes;
+pub mod merge;
+pub mod native;
+pub mod progressive;
+pub mod meta;
+pub mod render;
+pub mod reorder;
 pub mod trace;
+pub mod view;
+pub mod worker;
 
+pub use crate::error::{Error, Result};
+
+use crate::args::EventArgs;
 use crate::iforest::IForestIndex;
-use crate::index::{Aggregate, LongestEvent, TrackIndex};
-use crate::trace::{BlockPool, Ns, BlockIndex, Track};
+use crate::clock::{AbsNs, TimeBase};
+use crate::index::{Aggregate, BusyTime, LongestEvent, TrackIndex};
+use crate::instant::{InstantEvent, InstantTrackInfo};
+use crate::kinds::KindRegistry;
+use crate::meta::TrackMeta;
+use crate::trace::{BlockPool, Event, Ns, BlockIndex, Track};
 use std::ops::Range;
 use std::mem;
 use fastrand::Rng;
 
 
-pub fn aggregate_by_steps<A: Aggregate>(
-    pool: &BlockPool,
+/// Aggregates the events of a track into buckets `time_step` long covering
+/// `time_span`, by the bucket each event starts in. The first element holds
+/// every event before the span, so element `i` holds the events starting in
+/// `start + (i-1)*step .. start + i*step`. The result stops early if the
+/// track ends before the span does, with the last element holding the
+/// track's final events, so it's never longer than `1 + ceil(len/step)`.
+/// An empty track gives no elements at all.
+pub fn aggregate_by_steps<A: Aggregate<E>, E: Event, const N: usize>(
+    pool: &BlockPool<E, N>,
     block_locs: &[BlockIndex],
-    index: &IForestIndex<A>,
+    index: &IForestIndex<A, E, N>,
     time_span: Range<Ns>,
     time_step: u64,
 ) -> Vec<A> {
@@ -22,14 +59,14 @@ pub fn aggregate_by_steps<A: Aggregate>(
     let mut block_i = 0;
     let mut target_time = time_span.start;
     let mut combined = A::empty();
-    'outer: loop {
+    loop {
         if block_i >= block_locs.len() {
             break;
         }
 
         // == Skip to last block with a start_time before target_time
         let bsearch_res = block_locs[block_i..]
-            .binary_search_by_key(&target_time, |i| pool.blocks[*i as usize].start_time())
+            .binary_search_by_key(&target_time, |i| pool.start_time(*i))
             .unwrap_or_else(|i| i);
         if bsearch_res > 1 {
             let skip = bsearch_res - 1;
@@ -38,28 +75,39 @@ pub fn aggregate_by_steps<A: Aggregate>(
             block_i += skip;
         }
 
-        let block = &pool.blocks[block_locs[block_i] as usize];
-        for ev in block.events() {
-            let ev_ts = ev.ts.unpack();
+        // == Aggregate runs of events between bucket boundaries in one go
+        let block = pool.block(block_locs[block_i]);
+        let mut ev_i = 0;
+        loop {
+            let split = block.partition_ts(target_time).max(ev_i);
+            combined = A::combine(&combined, &A::from_events(&block, ev_i..split));
+            ev_i = split;
+            if ev_i == block.len() {
+                break;
+            }
+            let ev_ts = block.ts(ev_i);
             while ev_ts >= target_time {
                 // TODO add trait bool fn to allow skipping adding empty stuff to lists
                 out.push(mem::replace(&mut combined, A::empty()));
                 if target_time >= time_span.end {
-                    break 'outer;
+                    return out;
                 }
                 target_time = target_time + time_step;
             }
-            combined = A::combine(&combined, &A::from_event(ev));
         }
 
         block_i += 1;
     }
 
+    // Events ran out before the end of the span, finish the current bucket
+    if !block_locs.is_empty() {
+        out.push(combined);
+    }
     out
 }
 
-pub fn aggregate_by_steps_unindexed<A: Aggregate>(
-    pool: &BlockPool,
+pub fn aggregate_by_steps_unindexed<A: Aggregate<E>, E: Event, const N: usize>(
+    pool: &BlockPool<E, N>,
     block_locs: &[BlockIndex],
     time_span: Range<Ns>,
     time_step: u64,
@@ -68,32 +116,117 @@ pub fn aggregate_by_steps_unindexed<A: Aggregate>(
 
     let mut target_time = time_span.start;
     let mut combined = A::empty();
-    'outer: for block_i in block_locs {
-        let block = &pool.blocks[*block_i as usize];
-        for ev in block.events() {
-            let ev_ts = ev.ts.unpack();
+    for block_i in block_locs {
+        for ev in pool.block(*block_i) {
+            let ev_ts = ev.ts();
             while ev_ts >= target_time {
                 // TODO add trait bool fn to allow skipping adding empty stuff to lists
                 out.push(mem::replace(&mut combined, A::empty()));
                 if target_time >= time_span.end {
-                    break 'outer;
+                    return out;
                 }
                 target_time = target_time + time_step;
             }
-            combined = A::combine(&combined, &A::from_event(ev));
+            combined = A::combine(&combined, &A::from_event(&ev));
         }
     }
+    if !block_locs.is_empty() {
+        out.push(combined);
+    }
     out
 }
 
+fn block_starts(track: &Track, pool: &BlockPool) -> Vec<usize> {
+    let mut starts = Vec::with_capacity(track.block_locs.len() + 1);
+    let mut count = 0;
+    starts.push(0);
+    for &b in &track.block_locs {
+        count += pool.block(b).len();
+        starts.push(count);
+    }
+    starts
+}
+
 pub struct TrackInfo {
+    pub meta: TrackMeta,
     pub track: Track,
     pub zoom_index: IForestIndex<LongestEvent>,
+    pub busy_index: IForestIndex<BusyTime>,
+    /// The index in the track of the first event of each block, and then
+    /// the number of events, for counting without another index
+    pub block_starts: Vec<usize>,
+    pub args: EventArgs,
+}
+
+impl TrackInfo {
+    pub fn new(track: Track, pool: &BlockPool) -> Self {
+        TrackInfo {
+            meta: TrackMeta::default(),
+            zoom_index: IForestIndex::build(&track, pool),
+            busy_index: IForestIndex::build(&track, pool),
+            block_starts: block_starts(&track, pool),
+            track,
+            args: EventArgs::new(),
+        }
+    }
+
+    pub fn with_meta(mut self, meta: TrackMeta) -> Self {
+        self.meta = meta;
+        self
+    }
+
+    pub fn with_args(mut self, args: EventArgs) -> Self {
+        self.args = args;
+        self
+    }
+
+    /// For after the events of the track have been modified in place.
+    pub fn rebuild_indexes(&mut self, pool: &BlockPool) {
+        self.zoom_index = IForestIndex::build(&self.track, pool);
+        self.busy_index = IForestIndex::build(&self.track, pool);
+        self.block_starts = block_starts(&self.track, pool);
+    }
+
+    pub fn event_count(&self) -> usize {
+        self.block_starts.last().copied().unwrap_or(0)
+    }
+
+    /// The number of events starting before `t`, which is also the index of
+    /// the first one starting at or after it.
+    pub fn events_before(&self, pool: &BlockPool, t: Ns) -> usize {
+        let locs = &self.track.block_locs;
+        let b = locs.partition_point(|&b| pool.start_time(b) < t);
+        if b == 0 {
+            return 0;
+        }
+        self.block_starts[b - 1] + pool.block(locs[b - 1]).partition_ts(t)
+    }
+
+    /// The number of events starting in each step of `time_span`.
+    pub fn event_counts(&self, pool: &BlockPool, time_span: Range<Ns>, time_step: u64) -> Vec<usize> {
+        let mut before = self.events_before(pool, time_span.start);
+        (time_span.start..time_span.end).step_by(time_step as usize).map(|start| {
+            let next = self.events_before(pool, start.saturating_add(time_step));
+            next - mem::replace(&mut before, next)
+        }).collect()
+    }
+
+    /// Fraction of each step of `time_span` covered by events on this track.
+    pub fn busy_fractions(&self, pool: &BlockPool, time_span: Range<Ns>, time_step: u64) -> Vec<f64> {
+        let buckets = aggregate_by_steps(pool, &self.track.block_locs, &self.busy_index, time_span.clone(), time_step);
+        BusyTime::fractions(&buckets, time_span, time_step)
+    }
 }
 
 pub struct Trace {
     pub pool: BlockPool,
     pub tracks: Vec<TrackInfo>,
+    pub instant_pool: BlockPool<InstantEvent>,
+    pub instant_tracks: Vec<InstantTrackInfo>,
+    pub kinds: KindRegistry,
+    /// Event times are relative to this. The `_abs` query functions convert
+    /// through it, or use it to convert ranges and results for the others.
+    pub time_base: TimeBase,
 }
 
 impl Trace {
@@ -101,31 +234,47 @@ impl Trace {
         Trace {
             pool: BlockPool::new(),
             tracks: vec![],
+            instant_pool: BlockPool::default(),
+            instant_tracks: vec![],
+            kinds: KindRegistry::new(),
+            time_base: TimeBase::default(),
         }
     }
 
     pub fn demo_trace(tracks: usize, events_per_track: usize) -> Self {
+        Self::demo_trace_in(BlockPool::new(), tracks, events_per_track)
+    }
+
+    /// Like `demo_trace` but storing events in `pool`, to try other formats.
+    pub fn demo_trace_in(pool: BlockPool, tracks: usize, events_per_track: usize) -> Self {
         let mut trace = Self::new();
+        trace.pool = pool;
         let rng = Rng::new();
-        for _ in 0..tracks {
+        for i in 0..tracks {
             let mut track = Track::new();
             track.add_dummy_events(&mut trace.pool, &rng, events_per_track);
-            trace.tracks.push(TrackInfo {
-                zoom_index: IForestIndex::build(&track, &trace.pool),
-                track,
-            });
+            let meta = TrackMeta { name: format!("Track {}", i), ..TrackMeta::default() };
+            trace.tracks.push(TrackInfo::new(track, &trace.pool).with_meta(meta));
         }
         trace
     }
 
     pub fn time_bounds(&self) -> Option<Range<Ns>> {
-        let start = self.tracks.iter().filter_map(|t| t.track.start_time(&self.pool)).min();
-        let end = self.tracks.iter().filter_map(|t| t.track.after_last_time(&self.pool)).max();
+        let start = self.tracks.iter().filter_map(|t| t.track.start_time(&self.pool))
+            .chain(self.instant_tracks.iter().filter_map(|t| t.track.start_time(&self.instant_pool)))
+            .min();
+        let end = self.tracks.iter().filter_map(|t| t.track.after_last_time(&self.pool))
+            .chain(self.instant_tracks.iter().filter_map(|t| t.track.after_last_time(&self.instant_pool)))
+            .max();
         match (start, end) {
             (Some(s), Some(e)) => Some(s..e),
             (_, _) => None
         }
     }
+
+    pub fn absolute_time_bounds(&self) -> Option<Range<AbsNs>> {
+        self.time_bounds().map(|r| self.time_base.absolute_range(r))
+    }
 }
 
 #[cfg(test)]
@@ -150,7 +299,7 @@ mod tests {
         let mut maxes = vec![];
         for i in &track.block_locs {
             maxes.push(
-                LongestEvent::from_block(&pool.blocks[*i as usize])
+                LongestEvent::from_block(&pool.block(*i))
                     .0
                     .unwrap()
                     .dur
@@ -200,11 +349,49 @@ mod tests {
         }
 
         let span = 13..150;
-        let res = crate::aggregate_by_steps_unindexed::<TsSum>(&pool, &track.block_locs, span, 10);
+        let res = crate::aggregate_by_steps_unindexed::<TsSum, _, _>(&pool, &track.block_locs, span, 10);
         let res_ts = res.iter().map(|x| x.0).collect::<Vec<_>>();
         assert_eq!(&res_ts[..], &[10, 35, 0, 0, 0, 0, 0, 0, 0, 201, 0, 0, 0, 0, 150]);
     }
 
+    #[test]
+    fn aggregate_by_steps_tail() {
+        let mut pool = BlockPool::new();
+        let empty = Track::new();
+        let index = IForestIndex::<TsSum, _, 16>::build(&empty, &pool);
+        assert!(crate::aggregate_by_steps(&pool, &empty.block_locs, &index, 0..100, 10).is_empty());
+        assert!(crate::aggregate_by_steps_unindexed::<TsSum, _, 16>(&pool, &empty.block_locs, 0..100, 10).is_empty());
+
+        // The track ends mid-span, so its final events get a bucket of their own
+        let mut track = Track::new();
+        for t in &[5, 12, 31, 33] {
+            track.push(&mut pool, TraceEvent {
+                kind: 0,
+                ts: PackedNs::new(*t),
+                dur: PackedNs::new(0),
+            });
+        }
+        let index = IForestIndex::<TsSum, _, 16>::build(&track, &pool);
+        let res = crate::aggregate_by_steps(&pool, &track.block_locs, &index, 10..100, 10);
+        let unindexed = crate::aggregate_by_steps_unindexed::<TsSum, _, 16>(&pool, &track.block_locs, 10..100, 10);
+        let res_ts = res.iter().map(|x| x.0).collect::<Vec<_>>();
+        assert_eq!(&res_ts[..], &[5, 12, 0, 64]);
+        assert_eq!(unindexed.iter().map(|x| x.0).collect::<Vec<_>>(), res_ts);
+    }
+
+    #[test]
+    fn checked_ingestion() {
+        let mut pool = BlockPool::new();
+        let mut track = Track::new();
+        assert_eq!(TraceEvent::try_new(0, 1 << 48, 0).err(), Some(crate::Error::TimestampOverflow(1 << 48)));
+        assert_eq!(TraceEvent::try_new(0, 1 << 47, 1 << 47).err(), Some(crate::Error::TimestampOverflow(1 << 48)));
+        track.try_push(&mut pool, TraceEvent::try_new(0, 100, 5).unwrap()).unwrap();
+        track.try_push(&mut pool, TraceEvent::try_new(0, 100, 0).unwrap()).unwrap();
+        let res = track.try_push(&mut pool, TraceEvent::try_new(0, 99, 0).unwrap());
+        assert_eq!(res, Err(crate::Error::NonMonotonic { prev: 100, ts: 99 }));
+        assert_eq!(track.events(&pool).count(), 2);
+    }
+
     #[test]
     fn prop_test_range_query() {
         let mut pool = BlockPool::new();
@@ -221,12 +408,40 @@ mod tests {
             let end = rng.usize(start..=track.block_locs.len());
             let EventCount(count) = index.range_query(start..end);
             let correct: usize = track.block_locs[start..end].iter()
-                .map(|i| pool.blocks[*i as usize].len as usize).sum();
+                .map(|i| pool.block(*i).len()).sum();
             assert_eq!(count, correct, "failed for {}..{}", start, end);
         }
     }
 
 
+    #[test]
+    fn prop_test_busy_fractions() {
+        let mut pool = BlockPool::new();
+        let mut track = Track::new();
+        let rng = Rng::new();
+        track.add_dummy_events(&mut pool, &rng, 325);
+        let info = crate::TrackInfo::new(track, &pool);
+
+        let time_bounds = 0..=(info.track.after_last_time(&pool).unwrap()+100_000);
+        for _ in 0..1000 {
+            let t1 = rng.u64(time_bounds.clone());
+            let t2 = rng.u64(time_bounds.clone());
+            let t_range = if t2 > t1 { t1..t2 } else { t2..t1 };
+            let step = ((t_range.end - t_range.start) / rng.u64(1..50)) + rng.u64(1..100);
+            let res = info.busy_fractions(&pool, t_range.clone(), step);
+            assert_eq!(res.len() as u64, (t_range.end - t_range.start).div_ceil(step));
+            for (i, frac) in res.iter().enumerate() {
+                let lo = t_range.start + (i as u64) * step;
+                let hi = lo + step;
+                let correct: u64 = info.track.events(&pool).map(|ev| {
+                    let (s, e) = (ev.ts.unpack(), ev.ts.unpack() + ev.dur.unpack());
+                    e.min(hi).saturating_sub(s.max(lo))
+                }).sum();
+                assert_eq!((frac * step as f64).round() as u64, correct, "failed for {:?} - {} at {}", t_range, step, i);
+            }
+        }
+    }
+
     #[test]
     fn prop_test_aggregate_by_steps() {
         let mut pool = BlockPool::new();
@@ -245,9 +460,125 @@ mod tests {
             let t_range = if t2 > t1 { t1..t2 } else { t2..t1 };
             let range_size = t_range.end - t_range.start;
             let step = (range_size / rng.u64(1..10)) + rng.u64(0..100);
-            let res1 = crate::aggregate_by_steps::<TsSum>(&pool, &track.block_locs, &index, t_range.clone(), step);
-            let res2 = crate::aggregate_by_steps_unindexed::<TsSum>(&pool, &track.block_locs, t_range.clone(), step);
+            let res1 = crate::aggregate_by_steps::<TsSum, _, _>(&pool, &track.block_locs, &index, t_range.clone(), step);
+            let res2 = crate::aggregate_by_steps_unindexed::<TsSum, _, _>(&pool, &track.block_locs, t_range.clone(), step);
+            assert_eq!(res1, res2, "failed for {:?} - {}", t_range, step);
+        }
+    }
+
+    #[test]
+    fn large_blocks() {
+        let mut pool = BlockPool::<TraceEvent, 64>::default();
+        let mut track = Track::default();
+        let rng = Rng::with_seed(3);
+        track.add_dummy_events(&mut pool, &rng, 1000);
+        assert_eq!(track.block_locs.len(), 16);
+
+        let index = IForestIndex::<TsSum, TraceEvent, 64>::build(&track, &pool);
+        let end = track.end_time(&pool).unwrap();
+        for _ in 0..1000 {
+            let t1 = rng.u64(0..end);
+            let t_range = t1..rng.u64(t1..=end);
+            let step = (t_range.end - t_range.start) / rng.u64(1..10) + 1;
+            let res1 = crate::aggregate_by_steps(&pool, &track.block_locs, &index, t_range.clone(), step);
+            let res2 = crate::aggregate_by_steps_unindexed::<TsSum, _, 64>(&pool, &track.block_locs, t_range.clone(), step);
+            assert_eq!(res1, res2, "failed for {:?} - {}", t_range, step);
+        }
+    }
+
+    #[test]
+    fn soa_matches_raw() {
+        let raw = crate::Trace::demo_trace(3, 2000);
+        let mut soa = crate::Trace::new();
+        soa.pool = BlockPool::with_format(BlockFormat::Soa);
+        for info in &raw.tracks {
+            let mut track = Track::new();
+            for ev in info.track.events(&raw.pool) {
+                track.push(&mut soa.pool, ev);
+            }
+            soa.tracks.push(crate::TrackInfo::new(track, &soa.pool));
+        }
+        let rng = Rng::with_seed(11);
+        let end = raw.time_bounds().unwrap().end;
+        for (a, b) in raw.tracks.iter().zip(&soa.tracks) {
+            let count_index = IForestIndex::<EventCount>::build(&a.track, &raw.pool);
+            for _ in 0..200 {
+                let t1 = rng.u64(0..end);
+                let t_range = t1..rng.u64(t1..=end);
+                let step = (t_range.end - t_range.start) / rng.u64(1..50) + 1;
+                let durs = |info: &crate::TrackInfo, pool: &BlockPool| {
+                    crate::aggregate_by_steps(pool, &info.track.block_locs, &info.zoom_index, t_range.clone(), step)
+                        .iter()
+                        .map(|l| l.0.map(|ev| (ev.ts.unpack(), ev.dur.unpack())))
+                        .collect::<Vec<_>>()
+                };
+                assert_eq!(durs(a, &raw.pool), durs(b, &soa.pool));
+                let counts = |info: &crate::TrackInfo, pool: &BlockPool| info.event_counts(pool, t_range.clone(), step);
+                assert_eq!(counts(a, &raw.pool), counts(b, &soa.pool));
+                let mut indexed = crate::aggregate_by_steps(&raw.pool, &a.track.block_locs, &count_index, t_range.clone(), step)
+                    .iter()
+                    .skip(1)
+                    .map(|c| c.0)
+                    .collect::<Vec<_>>();
+                indexed.resize(counts(a, &raw.pool).len(), 0);
+                assert_eq!(counts(a, &raw.pool), indexed);
+            }
+        }
+    }
+
+    #[derive(Copy, Clone)]
+    struct Sample {
+        ts: u64,
+        value: u32,
+    }
+
+    impl Event for Sample {
+        const NULL: Self = Sample { ts: 0, value: 0 };
+
+        fn ts(&self) -> Ns {
+            self.ts
+        }
+    }
+
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    struct MaxValue(u32);
+
+    impl Aggregate<Sample> for MaxValue {
+        fn empty() -> Self {
+            MaxValue(0)
+        }
+
+        fn from_event(ev: &Sample) -> Self {
+            MaxValue(ev.value)
+        }
+
+        fn combine(&self, other: &Self) -> Self {
+            MaxValue(self.0.max(other.0))
+        }
+    }
+
+    #[test]
+    fn custom_events() {
+        let mut pool = BlockPool::<Sample>::default();
+        let mut track = Track::default();
+        let rng = Rng::with_seed(5);
+        for i in 0..500 {
+            track.try_push(&mut pool, Sample { ts: i * 1000 + rng.u64(..1000), value: rng.u32(..1_000_000) }).unwrap();
+        }
+        assert!(track.try_push(&mut pool, Sample { ts: 0, value: 0 }).is_err());
+
+        let maxes = IForestIndex::<MaxValue, Sample>::build(&track, &pool);
+        let counts = IForestIndex::<EventCount, Sample>::build(&track, &pool);
+        for _ in 0..1000 {
+            let t1 = rng.u64(0..500_000);
+            let t_range = t1..rng.u64(t1..=500_000);
+            let step = (t_range.end - t_range.start) / rng.u64(1..10) + 1;
+            let res1 = crate::aggregate_by_steps(&pool, &track.block_locs, &maxes, t_range.clone(), step);
+            let res2 = crate::aggregate_by_steps_unindexed(&pool, &track.block_locs, t_range.clone(), step);
             assert_eq!(res1, res2, "failed for {:?} - {}", t_range, step);
+            let counted = crate::aggregate_by_steps(&pool, &track.block_locs, &counts, t_range.clone(), step);
+            let unindexed = crate::aggregate_by_steps_unindexed::<EventCount, _, _>(&pool, &track.block_locs, t_range.clone(), step);
+            assert_eq!(counted.iter().map(|c| c.0).collect::<Vec<_>>(), unindexed.iter().map(|c| c.0).collect::<Vec<_>>());
         }
     }
 }
@@ -0,0 +1,271 @@
+use crate::clock::{AbsNs, ClockDomain, TimeBase};
+use crate::error::{Error, Result};
+use crate::instant::{InstantEvent, InstantTrackInfo};
+use crate::trace::{Ns, PackedNs, Track, EVENTS_PER_BLOCK};
+use crate::{Trace, TrackInfo};
+use std::convert::TryFrom;
+use std::ops::Range;
+
+/// How to map times from another trace's clock onto this one's.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ClockCorrection {
+    /// Added to every time from the other trace
+    pub offset: i64,
+    /// Fraction the other clock runs fast by, for example `1e-6` if it gains
+    /// a microsecond every second. Corrected for starting from the other
+    /// trace's base, so the offset should be measured there. Has to be
+    /// more than -1.
+    pub drift: f64,
+}
+
+impl ClockCorrection {
+    pub const NONE: ClockCorrection = ClockCorrection { offset: 0, drift: 0.0 };
+
+    pub fn offset(offset: i64) -> Self {
+        ClockCorrection { offset, drift: 0.0 }
+    }
+
+    fn apply(&self, ts: AbsNs, origin: AbsNs) -> Result<AbsNs> {
+        let elapsed = (ts - origin) as f64 / (1.0 + self.drift);
+        let corrected = origin as i128 + self.offset as i128 + elapsed.round() as i128;
+        // Corrected to before the clock's zero
+        AbsNs::try_from(corrected).map_err(|_| Error::BeforeTimeBase { ts, base: 0 })
+    }
+}
+
+impl Trace {
+    /// Moves the `TimeBase` of the trace, rewriting every event. Fails
+    /// without changing anything if some event wouldn't fit.
+    pub fn rebase(&mut self, base: AbsNs) -> Result<()> {
+        let new = self.check_rebase(base)?;
+        self.apply_rebase(new);
+        Ok(())
+    }
+
+    fn check_rebase(&self, base: AbsNs) -> Result<TimeBase> {
+        let new = TimeBase::new(self.time_base.clock, base);
+        if let Some(bounds) = self.absolute_time_bounds() {
+            new.to_relative(bounds.start)?;
+            new.to_relative(bounds.end)?;
+        }
+        Ok(new)
+    }
+
+    /// Only touches the blocks of tracks, whose times `check_rebase` covered.
+    fn apply_rebase(&mut self, new: TimeBase) {
+        let old = self.time_base;
+        // Can't fail since we checked the bounds
+        let move_ts = |ts: PackedNs| PackedNs::new(old.to_absolute(ts.unpack()) - new.base);
+        for info in &self.tracks {
+            for &i in &info.track.block_locs {
+                self.pool.modify(i, |block| {
+                    for ev in block.events_mut() {
+                        ev.ts = move_ts(ev.ts);
+                    }
+                });
+            }
+        }
+        for info in &self.instant_tracks {
+            for &i in &info.track.block_locs {
+                self.instant_pool.modify(i, |block| {
+                    for ev in block.events_mut() {
+                        ev.ts = move_ts(ev.ts);
+                    }
+                });
+            }
+        }
+        self.time_base = new;
+        for track in &mut self.tracks {
+            track.rebuild_indexes(&self.pool);
+        }
+        for track in &mut self.instant_tracks {
+            track.rebuild_index(&self.instant_pool);
+        }
+    }
+
+    /// Imports all the tracks of `other` into this trace, returning the
+    /// indices of the new tracks, and appends its instant tracks to
+    /// `instant_tracks`. Times are mapped through `correction` and
+    /// this trace is rebased if `other` starts earlier. Kinds are matched up
+    /// by name, kinds without names keep their number. The new tracks get a
+    /// new `TrackMeta::source` so their processes stay separate.
+    ///
+    /// Everything is converted before this trace is touched, so it's left as
+    /// it was if the merge fails.
+    pub fn merge(&mut self, other: Trace, correction: ClockCorrection) -> Result<Range<usize>> {
+        // Dividing by `1 + drift` has to give a finite, positive rate
+        if !(correction.drift > -1.0 && correction.drift.is_finite()) {
+            return Err(Error::InvalidFormat(format!("clock drift of {} is out of range", correction.drift)));
+        }
+        let origin = other.time_base.base;
+        let map = |ts: Ns| correction.apply(other.time_base.to_absolute(ts), origin);
+
+        let mut time_base = self.time_base;
+        if let Some(bounds) = other.time_bounds() {
+            let start = map(bounds.start)?;
+            if self.time_bounds().is_none() || start < self.time_base.base {
+                time_base = self.check_rebase(start)?;
+            }
+        }
+
+        let mut kinds = self.kinds.clone();
+        let kind_map = (0..other.kinds.len())
+            .map(|k| kinds.try_intern(other.kinds.name(k as u16).unwrap()))
+            .collect::<Result<Vec<_>>>()?;
+        let map_kind = |kind: u16| kind_map.get(kind as usize).copied().unwrap_or(kind);
+
+        let mut tracks = Vec::with_capacity(other.tracks.len());
+        for info in &other.tracks {
+            let mut events = Vec::new();
+            for ev in info.track.events(&other.pool) {
+                let ts = ev.ts.unpack();
+                let start = map(ts)?;
+                let end = map(ts + ev.dur.unpack())?;
+                events.push(time_base.event(map_kind(ev.kind), start, end - start)?);
+            }
+            check_sorted(events.iter().map(|ev| ev.ts.unpack()))?;
+            tracks.push(events);
+        }
+        let mut instant_tracks = Vec::with_capacity(other.instant_tracks.len());
+        for info in &other.instant_tracks {
+            let mut events = Vec::new();
+            for ev in info.track.events(&other.instant_pool) {
+                let ts = time_base.to_relative(map(ev.ts.unpack())?)?;
+                events.push(InstantEvent::new(map_kind(ev.kind), ts)?);
+            }
+            check_sorted(events.iter().map(|ev| ev.ts.unpack()))?;
+            instant_tracks.push(events);
+        }
+        fn blocks<T>(tracks: &[Vec<T>]) -> usize {
+            tracks.iter().map(|evs| evs.len().div_ceil(EVENTS_PER_BLOCK)).sum()
+        }
+        if blocks(&tracks) > self.pool.available() || blocks(&instant_tracks) > self.instant_pool.available() {
+            return Err(Error::BlockIndexExhausted);
+        }
+
+        // Nothing can fail from here on
+        if time_base != self.time_base {
+            self.apply_rebase(time_base);
+        }
+        if self.time_base.clock == ClockDomain::Unknown {
+            self.time_base.clock = other.time_base.clock;
+        }
+        self.kinds = kinds;
+        // Keep processes from the other trace apart from ours
+        let source_offset = self.tracks.iter().map(|t| t.meta.source + 1).max().unwrap_or(0);
+        let first = self.tracks.len();
+        for (info, events) in other.tracks.iter().zip(tracks) {
+            let mut track = Track::new();
+            for ev in events {
+                track.push(&mut self.pool, ev);
+            }
+            let mut meta = info.meta.clone();
+            meta.source += source_offset;
+            self.tracks.push(TrackInfo::new(track, &self.pool).with_meta(meta).with_args(info.args.clone()));
+        }
+        for (info, events) in other.instant_tracks.iter().zip(instant_tracks) {
+            let mut track = Track::default();
+            for ev in events {
+                track.push(&mut self.instant_pool, ev);
+            }
+            let mut meta = info.meta.clone();
+            meta.source += source_offset;
+            self.instant_tracks.push(InstantTrackInfo::new(track, &self.instant_pool).with_meta(meta));
+        }
+        Ok(first..self.tracks.len())
+    }
+}
+
+/// Clock corrections keep times in order, but a bad track in `other` might
+/// not have been.
+fn check_sorted(mut ts: impl Iterator<Item = Ns>) -> Result<()> {
+    let mut prev = match ts.next() {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+    for t in ts {
+        if t < prev {
+            return Err(Error::NonMonotonic { prev, ts: t });
+        }
+        prev = t;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::TraceEvent;
+
+    fn one_track(base: AbsNs, kind: &str, events: &[(Ns, Ns)]) -> Trace {
+        let mut trace = Trace::new();
+        trace.time_base = TimeBase::new(ClockDomain::BootTime, base);
+        let kind = trace.kinds.intern(kind);
+        let mut track = Track::new();
+        for &(ts, dur) in events {
+            track.push(&mut trace.pool, TraceEvent::try_new(kind, ts, dur).unwrap());
+        }
+        trace.tracks.push(TrackInfo::new(track, &trace.pool));
+        trace
+    }
+
+    #[test]
+    fn merge_with_offset() {
+        let mut a = one_track(1_000_000, "a", &[(0, 10), (100, 10)]);
+        a.kinds.intern("b");
+        let mut b = one_track(900_000, "b", &[(0, 10), (2000, 1000)]);
+        b.add_instant_track(Default::default(), vec![InstantEvent::new(0, 2000).unwrap()]).unwrap();
+        // b's clock is 50us behind and runs 10% fast
+        let res = a.merge(b, ClockCorrection { offset: 50_000, drift: 0.1 });
+        assert_eq!(res.unwrap(), 1..2);
+        assert_eq!(a.time_base.base, 950_000);
+        assert_eq!(a.absolute_time_bounds(), Some(950_000..1_000_110));
+
+        let evs = a.tracks[1].track.events(&a.pool).map(|ev| (ev.kind, ev.ts.unpack(), ev.dur.unpack())).collect::<Vec<_>>();
+        assert_eq!(evs, vec![(1, 0, 9), (1, 1818, 909)]);
+        let evs = a.tracks[0].track.events(&a.pool).map(|ev| ev.ts.unpack()).collect::<Vec<_>>();
+        assert_eq!(evs, vec![50_000, 50_100]);
+        assert_eq!(a.tracks[1].meta.source, 1);
+        let instants = a.instant_tracks[0].track.events(&a.instant_pool).map(|ev| (ev.kind, ev.ts.unpack())).collect::<Vec<_>>();
+        assert_eq!(instants, vec![(1, 1818)]);
+    }
+
+    #[test]
+    fn failed_merge_changes_nothing() {
+        let mut a = one_track(1_000_000, "a", &[(0, 10), (100, 10)]);
+        // Starts earlier so `a` would be rebased, but with its slow clock
+        // corrected the second event is too far from there to fit
+        let b = one_track(999_000, "b", &[(0, 10), (PackedNs::MAX / 2 + 1000, 10)]);
+        let res = a.merge(b, ClockCorrection { offset: 0, drift: -0.5 });
+        assert!(matches!(res, Err(Error::SpanTooLarge { .. })));
+        assert_eq!(a.time_base.base, 1_000_000);
+        assert_eq!(a.tracks.len(), 1);
+        assert_eq!(a.kinds.len(), 1);
+        let evs = a.tracks[0].track.events(&a.pool).map(|ev| ev.ts.unpack()).collect::<Vec<_>>();
+        assert_eq!(evs, vec![0, 100]);
+    }
+
+    #[test]
+    fn merge_into_instants_only() {
+        let mut a = Trace::new();
+        a.time_base = TimeBase::new(ClockDomain::BootTime, 1_000_000);
+        a.add_instant_track(Default::default(), vec![InstantEvent::new(0, 100).unwrap()]).unwrap();
+        let b = one_track(2_000_000, "b", &[(0, 10)]);
+        a.merge(b, ClockCorrection::NONE).unwrap();
+        // Not rebased past the instant
+        assert_eq!(a.time_base.base, 1_000_000);
+        assert_eq!(a.absolute_time_bounds(), Some(1_000_100..2_000_010));
+    }
+
+    #[test]
+    fn bad_drift() {
+        for drift in [-1.0, -2.0, f64::NAN, f64::INFINITY] {
+            let mut a = one_track(1_000_000, "a", &[(0, 10)]);
+            let b = one_track(999_000, "b", &[(0, 10)]);
+            let res = a.merge(b, ClockCorrection { offset: 0, drift });
+            assert!(matches!(res, Err(Error::InvalidFormat(_))));
+            assert_eq!(a.time_base.base, 1_000_000);
+            assert_eq!(a.tracks.len(), 1);
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use crate::heatmap::HeatmapMetric;
+use crate::trace::Ns;
+use crate::Trace;
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// Descriptive information about a track, filled in by importers.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct TrackMeta {
+    pub name: String,
+    pub pid: Option<u32>,
+    pub tid: Option<u32>,
+    pub process_name: Option<String>,
+    /// Tracks are ordered by this within their process, then by tid
+    pub sort_key: i64,
+    pub color: Option<[u8; 3]>,
+    /// Which trace the track came from when traces are merged, so pids from
+    /// different machines don't get grouped together.
+    pub source: u32,
+    /// Which row of an async track this is, see `Trace::add_async_track`
+    pub lane: Option<u32>,
+}
+
+/// A process and the indices of its tracks in `Trace::tracks` and
+/// `Trace::instant_tracks`, in display order. Tracks without a pid are
+/// collected in a process with no pid.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ProcessNode {
+    pub source: u32,
+    pub pid: Option<u32>,
+    pub name: Option<String>,
+    pub tracks: Vec<usize>,
+    pub instant_tracks: Vec<usize>,
+}
+
+impl Trace {
+    /// Groups tracks into processes, ordered by source and pid with tracks
+    /// without a pid last.
+    pub fn processes(&self) -> Vec<ProcessNode> {
+        type ByPid = BTreeMap<(u32, bool, Option<u32>), ProcessNode>;
+        fn node_for<'a>(by_pid: &'a mut ByPid, meta: &TrackMeta) -> &'a mut ProcessNode {
+            let node = by_pid.entry((meta.source, meta.pid.is_none(), meta.pid)).or_insert_with(|| ProcessNode {
+                source: meta.source,
+                pid: meta.pid,
+                name: None,
+                tracks: vec![],
+                instant_tracks: vec![],
+            });
+            if node.name.is_none() {
+                node.name = meta.process_name.clone();
+            }
+            node
+        }
+        let mut by_pid = ByPid::new();
+        for (i, info) in self.tracks.iter().enumerate() {
+            node_for(&mut by_pid, &info.meta).tracks.push(i);
+        }
+        for (i, info) in self.instant_tracks.iter().enumerate() {
+            node_for(&mut by_pid, &info.meta).instant_tracks.push(i);
+        }
+        let mut out = by_pid.into_values().collect::<Vec<_>>();
+        let order = |meta: &TrackMeta, i: usize| (meta.sort_key, meta.tid, i);
+        for process in &mut out {
+            process.tracks.sort_by_key(|&i| order(&self.tracks[i].meta, i));
+            process.instant_tracks.sort_by_key(|&i| order(&self.instant_tracks[i].meta, i));
+        }
+        out
+    }
+
+    /// A summary of all the tracks of a process as one row of buckets like
+    /// `Trace::heatmap`. Event counts are summed, busy fractions are summed
+    /// into the average number of busy tracks, and the longest event is the
+    /// longest on any track.
+    pub fn process_summary(&self, process: &ProcessNode, time_span: Range<Ns>, time_step: u64, metric: HeatmapMetric) -> Vec<f64> {
+        let heatmap = self.heatmap_of(&process.tracks, time_span, time_step, metric);
+        let mut out = vec![0.0f64; heatmap.buckets];
+        for i in 0..heatmap.tracks() {
+            for (sum, v) in out.iter_mut().zip(heatmap.row(i)) {
+                *sum = match metric {
+                    HeatmapMetric::LongestEvent => sum.max(*v),
+                    HeatmapMetric::EventCount | HeatmapMetric::BusyFraction => *sum + v,
+                };
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn process_tree() {
+        let mut trace = Trace::demo_trace(5, 100);
+        let metas = [(Some(2), Some(20), 0), (Some(1), Some(11), 0), (None, None, 0), (Some(2), Some(21), -1), (Some(1), Some(10), 0)];
+        for (info, &(pid, tid, sort_key)) in trace.tracks.iter_mut().zip(metas.iter()) {
+            info.meta = TrackMeta { pid, tid, sort_key, process_name: pid.map(|p| format!("proc {}", p)), ..Default::default() };
+        }
+        let procs = trace.processes();
+        let got = procs.iter().map(|p| (p.pid, p.name.as_deref(), p.tracks.clone())).collect::<Vec<_>>();
+        assert_eq!(got, vec![
+            (Some(1), Some("proc 1"), vec![4, 1]),
+            (Some(2), Some("proc 2"), vec![3, 0]),
+            (None, None, vec![2]),
+        ]);
+
+        let bounds = trace.time_bounds().unwrap();
+        let counts = trace.process_summary(&procs[0], bounds.clone(), 10_000, HeatmapMetric::EventCount);
+        let single = trace.heatmap(bounds, 10_000, HeatmapMetric::EventCount);
+        for (b, count) in counts.iter().enumerate() {
+            assert_eq!(*count, single.get(4, b) + single.get(1, b));
+        }
+    }
+}
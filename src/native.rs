@@ -0,0 +1,375 @@
+use crate::args::EventArgs;
+use crate::clock::{ClockDomain, TimeBase};
+use crate::error::{Error, Result};
+use crate::instant::InstantEvent;
+use crate::json::Json;
+use crate::meta::TrackMeta;
+use crate::trace::{TraceEvent, Track};
+use crate::{Trace, TrackInfo};
+use std::convert::TryInto;
+use std::io::Write;
+
+const MAGIC: &[u8; 8] = b"GIGATRC\0";
+const VERSION: u32 = 1;
+
+// The format is the header, then the kind names, then each track's meta,
+// events and args, then the same for instant tracks without args.
+// Everything after the header is varints, with event times as deltas from
+// the previous event on the track, which makes files a bit smaller than
+// `CompressedBlocks` without needing block boundaries. Args are stored by
+// the delta from the previous event with args, with values as JSON text.
+
+fn clock_code(clock: ClockDomain) -> u8 {
+    match clock {
+        ClockDomain::Unknown => 0,
+        ClockDomain::Monotonic => 1,
+        ClockDomain::BootTime => 2,
+        ClockDomain::Realtime => 3,
+    }
+}
+
+/// The writing side of `Reader`, writing straight through to `out`.
+struct Writer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Writer<W> {
+    fn bytes(&mut self, b: &[u8]) -> Result<()> {
+        self.out.write_all(b)?;
+        Ok(())
+    }
+
+    fn u8(&mut self, b: u8) -> Result<()> {
+        self.bytes(&[b])
+    }
+
+    /// Same encoding as `compress::write_varint`, without needing a `Vec`
+    fn varint(&mut self, mut x: u64) -> Result<()> {
+        let mut buf = [0; 10];
+        let mut n = 0;
+        while x >= 0x80 {
+            buf[n] = (x as u8) | 0x80;
+            x >>= 7;
+            n += 1;
+        }
+        buf[n] = x as u8;
+        self.bytes(&buf[..=n])
+    }
+
+    fn string(&mut self, s: &str) -> Result<()> {
+        self.varint(s.len() as u64)?;
+        self.bytes(s.as_bytes())
+    }
+
+    fn opt(&mut self, x: Option<u64>) -> Result<()> {
+        match x {
+            Some(x) => {
+                self.u8(1)?;
+                self.varint(x)
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn meta(&mut self, meta: &TrackMeta) -> Result<()> {
+        self.string(&meta.name)?;
+        self.opt(meta.pid.map(u64::from))?;
+        self.opt(meta.tid.map(u64::from))?;
+        match &meta.process_name {
+            Some(name) => {
+                self.u8(1)?;
+                self.string(name)?;
+            }
+            None => self.u8(0)?,
+        }
+        // Zigzag so small negative keys stay small
+        self.varint(((meta.sort_key << 1) ^ (meta.sort_key >> 63)) as u64)?;
+        match meta.color {
+            Some(rgb) => {
+                self.u8(1)?;
+                self.bytes(&rgb)?;
+            }
+            None => self.u8(0)?,
+        }
+        self.varint(meta.source as u64)?;
+        self.opt(meta.lane.map(u64::from))
+    }
+}
+
+/// Bounds checked reading, so truncated or corrupt files are an error
+/// rather than a panic.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn err(&self, msg: &str) -> Error {
+        Error::InvalidFormat(format!("{} at byte {}", msg, self.pos))
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(n).filter(|&end| end <= self.data.len())
+            .ok_or_else(|| self.err("unexpected end of file"))?;
+        let out = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(out)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut x = 0;
+        for shift in (0..64).step_by(7) {
+            let b = self.u8()?;
+            x |= ((b & 0x7f) as u64) << shift;
+            if b < 0x80 {
+                return Ok(x);
+            }
+        }
+        Err(self.err("varint too long"))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        self.varint()?.try_into().map_err(|_| self.err("value out of range"))
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        // Anything longer than the rest of the file is corrupt, and would
+        // otherwise make us allocate whatever it says
+        let n = self.varint()?;
+        if n > (self.data.len() - self.pos) as u64 {
+            return Err(self.err("length past the end of the file"));
+        }
+        Ok(n as usize)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let n = self.len()?;
+        let bytes = self.bytes(n)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| self.err("invalid UTF-8"))
+    }
+
+    fn opt<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<Option<T>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => f(self).map(Some),
+            _ => Err(self.err("bad option tag")),
+        }
+    }
+
+    fn meta(&mut self) -> Result<TrackMeta> {
+        Ok(TrackMeta {
+            name: self.string()?,
+            pid: self.opt(Self::u32)?,
+            tid: self.opt(Self::u32)?,
+            process_name: self.opt(Self::string)?,
+            sort_key: {
+                let z = self.varint()?;
+                ((z >> 1) as i64) ^ -((z & 1) as i64)
+            },
+            color: self.opt(|r| Ok(r.bytes(3)?.try_into().unwrap()))?,
+            source: self.u32()?,
+            lane: self.opt(Self::u32)?,
+        })
+    }
+}
+
+impl Trace {
+    /// Whether `data` starts like a file from `write_native`.
+    pub fn is_native(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    /// Writes the trace in gigatrace's own format, which is much smaller and
+    /// faster to load than the formats it was imported from. Indexes aren't
+    /// stored since they're quick to rebuild. Writes go straight to `out`,
+    /// so give it a `BufWriter` for files.
+    pub fn write_native(&self, out: &mut impl Write) -> Result<()> {
+        let mut w = Writer { out };
+        w.bytes(MAGIC)?;
+        w.bytes(&VERSION.to_le_bytes())?;
+        w.u8(clock_code(self.time_base.clock))?;
+        w.bytes(&self.time_base.base.to_le_bytes())?;
+
+        w.varint(self.kinds.len() as u64)?;
+        for kind in 0..self.kinds.len() {
+            w.string(self.kinds.name(kind as u16).unwrap())?;
+        }
+
+        w.varint(self.tracks.len() as u64)?;
+        for info in &self.tracks {
+            w.meta(&info.meta)?;
+            w.varint(info.event_count() as u64)?;
+            let mut prev = 0;
+            for ev in info.track.events(&self.pool) {
+                let ts = ev.ts.unpack();
+                w.varint(ev.kind as u64)?;
+                w.varint(ts - prev)?;
+                w.varint(ev.dur.unpack())?;
+                prev = ts;
+            }
+            w.varint(info.args.len() as u64)?;
+            let mut prev = 0;
+            for (i, args) in info.args.iter() {
+                w.varint((i - prev) as u64)?;
+                w.varint(args.len() as u64)?;
+                for (key, value) in args {
+                    w.string(key)?;
+                    w.string(&value.to_string())?;
+                }
+                prev = i;
+            }
+        }
+
+        w.varint(self.instant_tracks.len() as u64)?;
+        for info in &self.instant_tracks {
+            w.meta(&info.meta)?;
+            w.varint(info.track.events(&self.instant_pool).count() as u64)?;
+            let mut prev = 0;
+            for ev in info.track.events(&self.instant_pool) {
+                let ts = ev.ts.unpack();
+                w.varint(ev.kind as u64)?;
+                w.varint(ts - prev)?;
+                prev = ts;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a trace written by `write_native` into raw blocks and builds
+    /// its indexes.
+    pub fn read_native(data: &[u8]) -> Result<Trace> {
+        let mut r = Reader { data, pos: 0 };
+        if r.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(r.err("not a gigatrace file"));
+        }
+        let version = u32::from_le_bytes(r.bytes(4)?.try_into().unwrap());
+        if version != VERSION {
+            return Err(Error::InvalidFormat(format!("unsupported version {}", version)));
+        }
+        let clock = match r.u8()? {
+            0 => ClockDomain::Unknown,
+            1 => ClockDomain::Monotonic,
+            2 => ClockDomain::BootTime,
+            3 => ClockDomain::Realtime,
+            _ => return Err(r.err("unknown clock")),
+        };
+        let base = u64::from_le_bytes(r.bytes(8)?.try_into().unwrap());
+
+        let mut trace = Trace::new();
+        trace.time_base = TimeBase::new(clock, base);
+        for _ in 0..r.len()? {
+            let name = r.string()?;
+            trace.kinds.try_intern(&name)?;
+        }
+
+        let kind = |r: &mut Reader| r.varint()?.try_into().map_err(|_| r.err("kind out of range"));
+        for _ in 0..r.len()? {
+            let meta = r.meta()?;
+            let mut track = Track::new();
+            let mut ts = 0u64;
+            let events = r.len()?;
+            for _ in 0..events {
+                let kind = kind(&mut r)?;
+                ts = ts.checked_add(r.varint()?).ok_or_else(|| r.err("time overflow"))?;
+                let dur = r.varint()?;
+                track.try_push(&mut trace.pool, TraceEvent::try_new(kind, ts, dur)?)?;
+            }
+            let mut args = EventArgs::new();
+            let mut i = 0usize;
+            for _ in 0..r.len()? {
+                let delta = r.varint()?.try_into().map_err(|_| r.err("event index out of range"))?;
+                i = i.checked_add(delta).filter(|&i| i < events).ok_or_else(|| r.err("args for an event past the end of the track"))?;
+                let mut ev_args = vec![];
+                for _ in 0..r.len()? {
+                    let key = r.string()?;
+                    let value = Json::parse(&r.string()?).map_err(|_| r.err("invalid arg value"))?;
+                    ev_args.push((key, value));
+                }
+                args.insert(i, ev_args);
+            }
+            trace.tracks.push(TrackInfo::new(track, &trace.pool).with_meta(meta).with_args(args));
+        }
+
+        for _ in 0..r.len()? {
+            let meta = r.meta()?;
+            let mut events = vec![];
+            let mut ts = 0u64;
+            for _ in 0..r.len()? {
+                let kind = kind(&mut r)?;
+                ts = ts.checked_add(r.varint()?).ok_or_else(|| r.err("time overflow"))?;
+                events.push(InstantEvent::new(kind, ts)?);
+            }
+            trace.add_instant_track(meta, events)?;
+        }
+        if r.pos != data.len() {
+            return Err(r.err("trailing data"));
+        }
+        Ok(trace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ClockDomain;
+
+    #[test]
+    fn round_trip() {
+        let mut trace = Trace::demo_trace(3, 1000);
+        trace.time_base = TimeBase::new(ClockDomain::Realtime, 1_600_000_000_000_000_000);
+        trace.kinds.intern("draw");
+        trace.tracks[1].meta = TrackMeta {
+            name: "main".into(),
+            pid: Some(7),
+            tid: Some(8),
+            process_name: Some("app".into()),
+            sort_key: -3,
+            color: Some([1, 2, 3]),
+            source: 1,
+            lane: Some(0),
+        };
+        let args = vec![("url".to_owned(), Json::from("a.html")), ("sizes".to_owned(), Json::Arr(vec![Json::Num(1.5)]))];
+        trace.tracks[1].args.insert(3, args);
+        trace.tracks[1].args.insert(700, vec![("n".to_owned(), Json::Num(2.0))]);
+        // Further from the previous args than there are bytes left
+        trace.tracks[2].args.insert(990, vec![("last".to_owned(), Json::Bool(true))]);
+        let instants = (0..50).map(|i| InstantEvent::new(0, i * 1000).unwrap());
+        trace.add_instant_track(TrackMeta::default(), instants).unwrap();
+
+        let mut data = vec![];
+        trace.write_native(&mut data).unwrap();
+        assert!(Trace::is_native(&data));
+        // Write errors come back with the io::Error
+        let mut short = vec![0; data.len() / 2];
+        let err = trace.write_native(&mut &mut short[..]).unwrap_err();
+        assert!(matches!(err, Error::Io(ref e) if e.kind() == std::io::ErrorKind::WriteZero));
+        let again = Trace::read_native(&data).unwrap();
+        assert_eq!(again.time_base, trace.time_base);
+        assert_eq!(again.kinds.get("draw"), trace.kinds.get("draw"));
+        assert_eq!(again.tracks.len(), 3);
+        for (a, b) in again.tracks.iter().zip(&trace.tracks) {
+            assert_eq!(a.meta, b.meta);
+            let events = |t: &Trace, info: &TrackInfo| info.track.events(&t.pool)
+                .map(|ev| (ev.kind, ev.ts.unpack(), ev.dur.unpack()))
+                .collect::<Vec<_>>();
+            assert_eq!(events(&again, a), events(&trace, b));
+            assert_eq!(a.args, b.args);
+        }
+        assert_eq!(again.instant_tracks[0].track.events(&again.instant_pool).count(), 50);
+
+        // Truncation is an error at any point
+        for len in (0..data.len()).step_by(37) {
+            assert!(Trace::read_native(&data[..len]).is_err());
+        }
+
+        // As are args for events the track doesn't have
+        trace.tracks[2].args.insert(1000, vec![("n".to_owned(), Json::Null)]);
+        let mut data = vec![];
+        trace.write_native(&mut data).unwrap();
+        assert!(matches!(Trace::read_native(&data), Err(Error::InvalidFormat(_))));
+    }
+}
@@ -0,0 +1,150 @@
+use crate::cache::BucketCache;
+use crate::trace::Ns;
+use crate::view::{self, DrawItem, RowTrack};
+use crate::Trace;
+use std::ops::Range;
+use std::time::Instant;
+
+/// How much coarser than full detail each pass is, as a multiple of the
+/// pixel width of a bucket. Every row gets a rough pass before any row gets
+/// a detailed one, so something shows up everywhere quickly.
+const PASSES: [f64; 2] = [8.0, 1.0];
+
+/// Draw items for a view which are computed a bit at a time, so a viewer
+/// with thousands of tracks can stop when its frame budget runs out and
+/// carry on next frame, sharpening the picture as it goes.
+///
+/// Rows are queried in the order given, so put the visible ones first.
+pub struct ProgressiveView {
+    range: Range<Ns>,
+    width: f64,
+    rows: Vec<RowTrack>,
+    /// The most detailed items so far for each row, and which pass made them
+    items: Vec<Option<(usize, Vec<DrawItem>)>>,
+    /// Next unit of work, as a pass and an index into `rows`
+    pass: usize,
+    next_row: usize,
+}
+
+impl ProgressiveView {
+    pub fn new(range: Range<Ns>, width: f64, rows: Vec<RowTrack>) -> Self {
+        let items = vec![None; rows.len()];
+        ProgressiveView { range, width, rows, items, pass: 0, next_row: 0 }
+    }
+
+    /// Whether this is for the given view, otherwise it should be replaced.
+    pub fn is_for(&self, range: &Range<Ns>, width: f64, rows: &[RowTrack]) -> bool {
+        self.range == *range && self.width == width && self.rows == rows
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.pass == PASSES.len()
+    }
+
+    /// Queries rows until `deadline` passes or there's nothing left to do,
+    /// returning whether everything is at full detail. At least one row is
+    /// always queried so that a slow frame still makes progress. Slice
+    /// tracks go through `cache`, so after a small pan most rows are quick.
+    pub fn refine(&mut self, trace: &Trace, cache: &mut BucketCache, deadline: Instant) -> bool {
+        while self.step(trace, cache).is_some() {
+            if Instant::now() >= deadline {
+                break;
+            }
+        }
+        self.is_done()
+    }
+
+    /// Queries the next row, returning which one it was, or `None` if
+    /// everything is already at full detail.
+    pub fn step(&mut self, trace: &Trace, cache: &mut BucketCache) -> Option<usize> {
+        if self.rows.is_empty() {
+            self.pass = PASSES.len();
+        }
+        if self.is_done() {
+            return None;
+        }
+        let factor = PASSES[self.pass];
+        // Query as if the view were narrower, then stretch it back out
+        let width = (self.width / factor).max(1.0);
+        let stretch = self.width / width;
+        let mut items = match self.rows[self.next_row] {
+            RowTrack::Track(i) => cache.track_items(trace, i, &self.range, width),
+            RowTrack::Instant(i) => view::instant_items(trace, &trace.instant_tracks[i], &self.range, width),
+        };
+        if stretch != 1.0 {
+            for item in &mut items {
+                match item {
+                    DrawItem::Span { x0, x1, .. } | DrawItem::Instants { x0, x1, .. } => {
+                        *x0 *= stretch;
+                        *x1 *= stretch;
+                    }
+                }
+            }
+        }
+        let row = self.next_row;
+        self.items[row] = Some((self.pass, items));
+        self.next_row += 1;
+        if self.next_row == self.rows.len() {
+            self.next_row = 0;
+            self.pass += 1;
+        }
+        Some(row)
+    }
+
+    /// The best items so far for the `row`th of the rows passed to `new`,
+    /// or `None` if it hasn't been queried yet.
+    pub fn items(&self, row: usize) -> Option<&[DrawItem]> {
+        self.items.get(row)?.as_ref().map(|(_, items)| &items[..])
+    }
+
+    /// Whether the items for `row` are at full detail.
+    pub fn is_row_done(&self, row: usize) -> bool {
+        matches!(self.items.get(row), Some(Some((pass, _))) if *pass == PASSES.len() - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refines_to_full_detail() {
+        let trace = Trace::demo_trace(4, 20_000);
+        let range = trace.time_bounds().unwrap();
+        let rows = vec![RowTrack::Track(2), RowTrack::Track(0), RowTrack::Track(1), RowTrack::Track(3)];
+        let mut progress = ProgressiveView::new(range.clone(), 500.0, rows.clone());
+        let mut cache = BucketCache::new(1 << 20);
+        assert!(progress.items(0).is_none());
+
+        // A deadline that's already passed still does one row per call
+        let past = Instant::now();
+        assert!(!progress.refine(&trace, &mut cache, past));
+        assert!(progress.items(0).is_some() && progress.items(1).is_none());
+        assert!(!progress.is_row_done(0));
+        for _ in 0..3 {
+            progress.refine(&trace, &mut cache, past);
+        }
+        // Every row has a coarse result before any is detailed
+        assert!((0..4).all(|r| progress.items(r).is_some() && !progress.is_row_done(r)));
+        let coarse = progress.items(0).unwrap().to_vec();
+        assert!(coarse.len() < view::track_items(&trace, &trace.tracks[2], &range, 500.0).len());
+        assert!(coarse.iter().all(|item| match item {
+            DrawItem::Span { x0, x1, .. } => *x1 > *x0 && *x0 < 500.0,
+            _ => false,
+        }));
+
+        let far = Instant::now() + std::time::Duration::from_secs(60);
+        assert!(progress.refine(&trace, &mut cache, far));
+        for (r, row) in rows.iter().enumerate() {
+            let i = match row {
+                RowTrack::Track(i) => *i,
+                _ => unreachable!(),
+            };
+            assert!(progress.is_row_done(r));
+            assert_eq!(progress.items(r).unwrap(), &view::track_items(&trace, &trace.tracks[i], &range, 500.0)[..]);
+        }
+        assert!(progress.is_for(&range, 500.0, &rows) && !progress.is_for(&range, 501.0, &rows));
+        assert!(!progress.is_for(&range, 500.0, &rows[1..]));
+        assert!(ProgressiveView::new(range, 500.0, vec![]).refine(&trace, &mut cache, past));
+    }
+}
@@ -0,0 +1,223 @@
+use crate::error::Result;
+use crate::trace::Ns;
+use crate::view::{self, DrawItem, RowTrack};
+use crate::Trace;
+use std::io::Write;
+use std::ops::Range;
+
+/// A filled rectangle in pixels, drawn over white.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rect {
+    pub x0: f64,
+    pub y0: f64,
+    pub x1: f64,
+    pub y1: f64,
+    pub color: [u8; 3],
+    pub alpha: f64,
+}
+
+/// A picture of the tracks of a trace over a time range, drawn the way the
+/// UI draws them, for when there's no display like in CI or a bug report.
+/// Only the SVG has track labels, since rasterizing text would need a font.
+pub struct Snapshot {
+    pub width: u32,
+    pub height: u32,
+    pub rects: Vec<Rect>,
+    /// Track labels and the y of their row
+    pub labels: Vec<(f64, String)>,
+}
+
+impl Snapshot {
+    /// Lays out the tracks of `trace` with `view::rows`, until they run
+    /// out of room.
+    pub fn new(trace: &Trace, range: Range<Ns>, width: u32, height: u32) -> Snapshot {
+        let mut snap = Snapshot { width, height, rects: vec![], labels: vec![] };
+        for row in view::rows(trace).into_iter().take_while(|row| row.y < height as f64) {
+            let items = match row.track {
+                RowTrack::Track(i) => view::track_items(trace, &trace.tracks[i], &range, width as f64),
+                RowTrack::Instant(i) => view::instant_items(trace, &trace.instant_tracks[i], &range, width as f64),
+            };
+            for item in items {
+                let color = view::item_color(&row, &item);
+                let (x0, x1, alpha) = match item {
+                    DrawItem::Span { x0, x1, density, .. } => (x0, x1, 0.2 + 0.8 * density),
+                    DrawItem::Instants { x0, x1, .. } => (x0, x1, 1.0),
+                };
+                snap.rects.push(Rect { x0, y0: row.y, x1, y1: row.y + row.height, color, alpha });
+            }
+            if let Some(label) = row.label {
+                snap.labels.push((row.y, label));
+            }
+        }
+        snap
+    }
+
+    pub fn write_svg(&self, out: &mut impl Write) -> Result<()> {
+        writeln!(out, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#, w = self.width, h = self.height)?;
+        writeln!(out, r#"<rect width="100%" height="100%" fill="white"/>"#)?;
+        for r in &self.rects {
+            let [red, green, blue] = r.color;
+            writeln!(out, r#"<rect x="{:.2}" y="{}" width="{:.2}" height="{}" fill="rgb({},{},{})" fill-opacity="{:.3}"/>"#,
+                r.x0, r.y0, r.x1 - r.x0, r.y1 - r.y0, red, green, blue, r.alpha)?;
+        }
+        for (y, label) in &self.labels {
+            let text = label.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+            writeln!(out, r#"<text x="4" y="{}" font-family="sans-serif" font-size="12" fill="red">{}</text>"#, y + 14.0, text)?;
+        }
+        writeln!(out, "</svg>")?;
+        Ok(())
+    }
+
+    /// Rasterizes the rectangles into 8-bit RGB rows, blending partly
+    /// covered pixels at the ends of each rectangle by their coverage.
+    pub fn to_rgb(&self) -> Vec<u8> {
+        let (w, h) = (self.width as usize, self.height as usize);
+        let mut px = vec![255u8; w * h * 3];
+        for r in &self.rects {
+            let (x0, x1) = (r.x0.max(0.0), r.x1.min(w as f64));
+            let (y0, y1) = (r.y0.max(0.0) as usize, (r.y1.min(h as f64)) as usize);
+            if x0 >= x1 {
+                continue;
+            }
+            for x in x0.floor() as usize..(x1.ceil() as usize).min(w) {
+                let coverage = (x1.min(x as f64 + 1.0) - x0.max(x as f64)).clamp(0.0, 1.0);
+                let a = r.alpha * coverage;
+                for y in y0..y1 {
+                    let p = &mut px[(y * w + x) * 3..][..3];
+                    for (c, &src) in p.iter_mut().zip(&r.color) {
+                        *c = (*c as f64 * (1.0 - a) + src as f64 * a).round() as u8;
+                    }
+                }
+            }
+        }
+        px
+    }
+
+    /// Binary PPM, the simplest image format most tools can open.
+    pub fn write_ppm(&self, out: &mut impl Write) -> Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        out.write_all(&self.to_rgb())?;
+        Ok(())
+    }
+
+    /// A PNG with stored (uncompressed) deflate blocks, which any viewer can
+    /// open without us needing a compressor.
+    pub fn write_png(&self, out: &mut impl Write) -> Result<()> {
+        let rgb = self.to_rgb();
+        let row_len = self.width as usize * 3;
+        let mut raw = Vec::with_capacity((row_len + 1) * self.height as usize);
+        for row in rgb.chunks(row_len.max(1)).take(self.height as usize) {
+            // Filter type none
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.extend_from_slice(&self.width.to_be_bytes());
+        ihdr.extend_from_slice(&self.height.to_be_bytes());
+        // 8 bits per channel RGB, deflate, no filtering or interlace
+        ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+        out.write_all(b"\x89PNG\r\n\x1a\n")?;
+        write_chunk(out, b"IHDR", &ihdr)?;
+        write_chunk(out, b"IDAT", &zlib_stored(&raw))?;
+        write_chunk(out, b"IEND", &[])?;
+        Ok(())
+    }
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut chunks = data.chunks(0xffff).peekable();
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(chunk) = chunks.next() {
+        out.push(chunks.peek().is_none() as u8);
+        let len = chunk.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // Sums can't overflow in this many bytes before taking the modulus
+    for chunk in data.chunks(5552) {
+        for &x in chunk {
+            a += x as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for &x in parts.iter().flat_map(|p| p.iter()) {
+        crc ^= x as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn write_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    out.write_all(&crc32(&[kind, data]).to_be_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(&[b"IEND"]), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut trace = Trace::demo_trace(3, 10_000);
+        trace.tracks[1].meta.color = Some([1, 2, 3]);
+        let bounds = trace.time_bounds().unwrap();
+        let snap = Snapshot::new(&trace, bounds, 200, 70);
+        // The second track's own color is used over the kind colors
+        assert!(snap.rects.iter().filter(|r| r.y0 == 30.0).all(|r| r.color == [1, 2, 3]));
+        assert!(snap.rects.iter().filter(|r| r.y0 == 0.0).all(|r| r.color != [1, 2, 3]));
+        // Two full tracks and the top of the third
+        assert_eq!(snap.labels.iter().map(|l| l.0).collect::<Vec<_>>(), vec![0.0, 30.0, 60.0]);
+        // Buckets can stick out past the edges, since the quantized range is
+        // a bucket wider on each side
+        assert!(snap.rects.iter().all(|r| r.x1 > r.x0 && r.x0 < 200.0));
+
+        let rgb = snap.to_rgb();
+        assert_eq!(rgb.len(), 200 * 70 * 3);
+        // The demo tracks are busy the whole way across
+        assert!(rgb[(15 * 200 + 100) * 3] < 255);
+
+        let mut png = vec![];
+        snap.write_png(&mut png).unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert!(png.ends_with(&[0xae, 0x42, 0x60, 0x82]));
+        // Header, chunk framing, zlib framing, a stored block header per 64K
+        let raw: usize = 70 * (200 * 3 + 1);
+        assert_eq!(png.len(), 8 + 12 * 3 + 13 + 6 + 5 * raw.div_ceil(0xffff) + raw);
+
+        let mut svg = vec![];
+        snap.write_svg(&mut svg).unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+        assert_eq!(svg.matches("<rect").count(), snap.rects.len() + 1);
+        assert!(svg.contains(">Track 0</text>"));
+    }
+}
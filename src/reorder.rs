@@ -0,0 +1,315 @@
+use crate::error::Result;
+use crate::trace::{BlockPool, Ns, PackedNs, TraceEvent, Track};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::convert::TryInto;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+struct Pending {
+    ts: Ns,
+    /// Arrival order, so events with equal timestamps keep their order
+    seq: u64,
+    ev: TraceEvent,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.ts, self.seq).cmp(&(other.ts, other.seq))
+    }
+}
+
+/// Late events are sorted and written out to a temporary file every time
+/// this many have built up.
+const RUN_EVENTS: usize = 1 << 16;
+
+/// Sits in front of a `Track` for sources which deliver events slightly out
+/// of order, holding events until no event arriving later can be more than
+/// `window` earlier than them.
+///
+/// Events which arrive later than that are set aside, and `finish` falls
+/// back to an external sort if there were any, so the result is always
+/// sorted but badly ordered input is slow. Late events are spilled to
+/// temporary files in sorted runs, which are then merged with the track.
+pub struct ReorderBuffer {
+    window: Ns,
+    pending: BinaryHeap<Reverse<Pending>>,
+    seq: u64,
+    latest: Ns,
+    committed: Option<Ns>,
+    late: Vec<TraceEvent>,
+    runs: Vec<Run>,
+    run_events: usize,
+    late_events: usize,
+}
+
+impl ReorderBuffer {
+    pub fn new(window: Ns) -> Self {
+        ReorderBuffer {
+            window,
+            pending: BinaryHeap::new(),
+            seq: 0,
+            latest: 0,
+            committed: None,
+            late: vec![],
+            runs: vec![],
+            run_events: RUN_EVENTS,
+            late_events: 0,
+        }
+    }
+
+    pub fn push<const N: usize>(&mut self, track: &mut Track<TraceEvent, N>, pool: &mut BlockPool<TraceEvent, N>, ev: TraceEvent) -> Result<()> {
+        let ts = ev.ts.unpack();
+        if self.committed.is_some_and(|c| ts < c) {
+            self.late.push(ev);
+            self.late_events += 1;
+            if self.late.len() >= self.run_events {
+                self.runs.push(Run::spill(&mut self.late)?);
+            }
+            return Ok(());
+        }
+        self.pending.push(Reverse(Pending { ts, seq: self.seq, ev }));
+        self.seq += 1;
+        self.latest = self.latest.max(ts);
+        while let Some(Reverse(p)) = self.pending.peek() {
+            if p.ts.saturating_add(self.window) > self.latest {
+                break;
+            }
+            self.commit(track, pool)?;
+        }
+        Ok(())
+    }
+
+    /// Pushes the earliest pending event, leaving it pending if that fails.
+    fn commit<const N: usize>(&mut self, track: &mut Track<TraceEvent, N>, pool: &mut BlockPool<TraceEvent, N>) -> Result<()> {
+        let Reverse(p) = self.pending.peek().unwrap();
+        track.try_push(pool, p.ev)?;
+        self.committed = Some(p.ts);
+        self.pending.pop();
+        Ok(())
+    }
+
+    /// Number of events so far that arrived too late for the window.
+    pub fn late_events(&self) -> usize {
+        self.late_events
+    }
+
+    /// Commits everything still buffered, merging in any events that
+    /// arrived too late.
+    pub fn finish<const N: usize>(mut self, track: &mut Track<TraceEvent, N>, pool: &mut BlockPool<TraceEvent, N>) -> Result<()> {
+        while !self.pending.is_empty() {
+            self.commit(track, pool)?;
+        }
+        if self.late_events > 0 {
+            // Stable, so late events go after earlier late ones with the same timestamp
+            self.late.sort_by_key(|ev| ev.ts.unpack());
+            merge_late(track, pool, self.runs, self.late)?;
+        }
+        Ok(())
+    }
+}
+
+/// A sorted run of late events in a temporary file, deleted when dropped.
+struct Run {
+    path: PathBuf,
+    file: BufReader<File>,
+    left: usize,
+}
+
+impl Run {
+    const EVENT_BYTES: usize = 2 + 8 + 8;
+
+    /// Sorts `events` and moves them out to a new file.
+    fn spill(events: &mut Vec<TraceEvent>) -> Result<Run> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        let id = NEXT_ID.fetch_add(1, AtomicOrdering::Relaxed);
+        let path = env::temp_dir().join(format!("gigatrace-{}-{}.run", process::id(), id));
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        // Made straight away so the file is cleaned up if writing fails
+        let mut run = Run { path, file: BufReader::new(file), left: events.len() };
+
+        events.sort_by_key(|ev| ev.ts.unpack());
+        let mut out = BufWriter::new(run.file.get_mut());
+        for ev in events.drain(..) {
+            out.write_all(&ev.kind.to_le_bytes())?;
+            out.write_all(&ev.ts.unpack().to_le_bytes())?;
+            out.write_all(&ev.dur.unpack().to_le_bytes())?;
+        }
+        out.into_inner().map_err(|e| e.into_error())?.seek(SeekFrom::Start(0))?;
+        Ok(run)
+    }
+
+    fn next(&mut self) -> Result<Option<TraceEvent>> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        let mut buf = [0; Self::EVENT_BYTES];
+        self.file.read_exact(&mut buf)?;
+        let u64_at = |i: usize| u64::from_le_bytes(buf[i..i + 8].try_into().unwrap());
+        Ok(Some(TraceEvent {
+            kind: u16::from_le_bytes([buf[0], buf[1]]),
+            ts: PackedNs::new(u64_at(2)),
+            dur: PackedNs::new(u64_at(10)),
+        }))
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Rebuilds `track` from a merge of its own events, the spilled `runs` and
+/// the sorted `late` events still in memory. Each of the track's blocks is
+/// freed once it has been read, so the new blocks mostly reuse them.
+///
+/// Ties go to the track, then to runs in the order they were spilled, so
+/// events keep their arrival order among ones with the same timestamp.
+fn merge_late<const N: usize>(track: &mut Track<TraceEvent, N>, pool: &mut BlockPool<TraceEvent, N>, mut runs: Vec<Run>, late: Vec<TraceEvent>) -> Result<()> {
+    let old = mem::take(&mut track.block_locs);
+    let (mut block, mut pos) = (0, 0);
+    let mut late = late.into_iter();
+    let sources = runs.len() + 2;
+    // Source 0 is the old track, then the runs, then `late`
+    let mut next = |src: usize, pool: &mut BlockPool<TraceEvent, N>| -> Result<Option<TraceEvent>> {
+        if src == 0 {
+            let Some(&i) = old.get(block) else { return Ok(None) };
+            let ev = pool.block(i).event(pos);
+            pos += 1;
+            if pos == pool.block(i).len() {
+                pool.free(i);
+                block += 1;
+                pos = 0;
+            }
+            Ok(Some(ev))
+        } else if src <= runs.len() {
+            runs[src - 1].next()
+        } else {
+            Ok(late.next())
+        }
+    };
+
+    let mut heads = vec![None; sources];
+    let mut order = BinaryHeap::with_capacity(sources);
+    for (src, head) in heads.iter_mut().enumerate() {
+        *head = next(src, pool)?;
+        if let Some(ev) = head {
+            order.push(Reverse((ev.ts.unpack(), src)));
+        }
+    }
+    while let Some(Reverse((_, src))) = order.pop() {
+        track.try_push(pool, heads[src].unwrap())?;
+        heads[src] = next(src, pool)?;
+        if let Some(ev) = heads[src] {
+            order.push(Reverse((ev.ts.unpack(), src)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::EVENTS_PER_BLOCK;
+    use fastrand::Rng;
+
+    /// Events every 100ns delayed by up to `max_delay`
+    fn shuffled(rng: &Rng, max_delay: u64) -> Vec<TraceEvent> {
+        (0..1000u64).map(|i| {
+            let ts = i * 100 + rng.u64(..=max_delay);
+            TraceEvent { kind: 0, ts: PackedNs::new(ts), dur: PackedNs::new(10) }
+        }).collect()
+    }
+
+    fn check_sorted(track: &Track, pool: &BlockPool) {
+        let ts = track.events(pool).map(|ev| ev.ts.unpack()).collect::<Vec<_>>();
+        assert_eq!(ts.len(), 1000);
+        assert!(ts.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn reorder_within_window() {
+        let rng = Rng::new();
+        let mut pool = BlockPool::new();
+        let mut track = Track::new();
+        let mut buf = ReorderBuffer::new(1000);
+        for ev in shuffled(&rng, 1000) {
+            buf.push(&mut track, &mut pool, ev).unwrap();
+        }
+        assert_eq!(buf.late_events(), 0);
+        buf.finish(&mut track, &mut pool).unwrap();
+        check_sorted(&track, &pool);
+    }
+
+    #[test]
+    fn reorder_fallback_sort() {
+        let rng = Rng::new();
+        let mut pool = BlockPool::new();
+        let mut track = Track::new();
+        let mut buf = ReorderBuffer::new(100);
+        buf.run_events = 100;
+        for ev in shuffled(&rng, 50_000) {
+            buf.push(&mut track, &mut pool, ev).unwrap();
+        }
+        let late = buf.late_events();
+        assert!(late > 0);
+        assert_eq!(buf.runs.len(), late / 100);
+        let paths = buf.runs.iter().map(|run| run.path.clone()).collect::<Vec<_>>();
+        buf.finish(&mut track, &mut pool).unwrap();
+        check_sorted(&track, &pool);
+        assert!(paths.iter().all(|path| !path.exists()));
+        // The merge reuses the track's blocks, only allocating room for the late events
+        assert!(pool.len() <= track.block_locs.len() + late / EVENTS_PER_BLOCK + 1);
+    }
+
+    #[test]
+    fn failed_commit_keeps_event() {
+        let mut pool = BlockPool::new();
+        let mut track = Track::new();
+        let mut buf = ReorderBuffer::new(0);
+        let ev = |ts| TraceEvent { kind: 0, ts: PackedNs::new(ts), dur: PackedNs::new(0) };
+        buf.push(&mut track, &mut pool, ev(10)).unwrap();
+        // Something else pushed a later event, so committing ours fails
+        track.try_push(&mut pool, ev(20)).unwrap();
+        assert!(buf.push(&mut track, &mut pool, ev(15)).is_err());
+        assert_eq!(buf.pending.len(), 1);
+    }
+
+    #[test]
+    fn rewrite_frees_blocks() {
+        let mut pool = BlockPool::new();
+        let mut track = Track::new();
+        let events = shuffled(&Rng::with_seed(1), 0);
+        track.rewrite(&mut pool, &events).unwrap();
+        let blocks = pool.len();
+        track.rewrite(&mut pool, &events[..10]).unwrap();
+        assert_eq!(track.block_locs.len(), 1);
+        // The blocks given up are handed out again
+        let mut other = Track::new();
+        other.rewrite(&mut pool, &events).unwrap();
+        assert_eq!(pool.len(), blocks + 1);
+        assert_eq!(other.events(&pool).count(), 1000);
+        assert_eq!(track.events(&pool).count(), 10);
+    }
+}
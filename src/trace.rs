@@ -1,4 +1,8 @@
+use crate::compress::CompressedBlocks;
+use crate::error::{Error, Result};
 use fastrand::Rng;
+use std::marker::PhantomData;
+use std::mem;
 use std::ops::Range;
 
 pub type Ns = u64;
@@ -6,12 +10,22 @@ pub type Ns = u64;
 pub struct PackedNs([u8; 6]);
 
 impl PackedNs {
+    pub const MAX: Ns = (1 << 48) - 1;
+
+    /// Silently drops the top 16 bits, see `try_new` for input that might
+    /// not fit.
     pub const fn new(ts: Ns) -> Self {
         let b = ts.to_le_bytes();
-        // assert!(b[6] == 0 && b[7] == 0);
         PackedNs([b[0], b[1], b[2], b[3], b[4], b[5]])
     }
 
+    pub fn try_new(ts: Ns) -> Result<Self> {
+        if ts > Self::MAX {
+            return Err(Error::TimestampOverflow(ts));
+        }
+        Ok(Self::new(ts))
+    }
+
     #[inline]
     pub fn unpack(self) -> Ns {
         let b = self.0;
@@ -19,6 +33,29 @@ impl PackedNs {
     }
 }
 
+/// Something that can be stored on a `Track`, which keeps events in `ts`
+/// order. Implement this to store things other than `TraceEvent`s, like
+/// counter samples, in a `BlockPool` and index them with `IForestIndex`.
+pub trait Event: Copy {
+    /// Fills the unused slots of a block
+    const NULL: Self;
+
+    fn ts(&self) -> Ns;
+
+    /// How long the event lasts, 0 for instants and samples.
+    fn dur(&self) -> Ns {
+        0
+    }
+}
+
+/// Events which can be converted to and from a `TraceEvent` without losing
+/// anything, which lets them be stored in the `Soa` and `Compressed` block
+/// formats.
+pub trait PackableEvent: Event {
+    fn to_trace_event(&self) -> TraceEvent;
+    fn from_trace_event(ev: TraceEvent) -> Self;
+}
+
 #[derive(Copy, Clone)]
 pub struct TraceEvent {
     pub kind: u16,
@@ -26,96 +63,622 @@ pub struct TraceEvent {
     pub dur: PackedNs,
 }
 
+impl TraceEvent {
+    /// Fails unless the start, duration and end all fit in 48 bits.
+    pub fn try_new(kind: u16, ts: Ns, dur: Ns) -> Result<Self> {
+        let end = ts.saturating_add(dur);
+        if end > PackedNs::MAX {
+            return Err(Error::TimestampOverflow(end));
+        }
+        Ok(TraceEvent {
+            kind,
+            ts: PackedNs::try_new(ts)?,
+            dur: PackedNs::try_new(dur)?,
+        })
+    }
+}
+
 pub const NULL_EVENT: TraceEvent = TraceEvent {
     kind: 0,
     ts: PackedNs::new(0),
     dur: PackedNs::new(0),
 };
 
+impl Event for TraceEvent {
+    const NULL: Self = NULL_EVENT;
+
+    #[inline]
+    fn ts(&self) -> Ns {
+        self.ts.unpack()
+    }
+
+    #[inline]
+    fn dur(&self) -> Ns {
+        self.dur.unpack()
+    }
+}
+
+impl PackableEvent for TraceEvent {
+    fn to_trace_event(&self) -> TraceEvent {
+        *self
+    }
+
+    fn from_trace_event(ev: TraceEvent) -> Self {
+        ev
+    }
+}
+
+/// The `PackableEvent` conversions of a pool's event type, kept as function
+/// pointers so the `BlockPool` methods which don't know about them still
+/// work for any event type.
+pub(crate) struct Codec<E> {
+    pub pack: fn(&E) -> TraceEvent,
+    pub unpack: fn(TraceEvent) -> E,
+}
+
+impl<E> Clone for Codec<E> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<E> Copy for Codec<E> {}
+
+impl<E: PackableEvent> Codec<E> {
+    pub fn new() -> Self {
+        Codec { pack: E::to_trace_event, unpack: E::from_trace_event }
+    }
+}
+
 pub type BlockIndex = u32;
 
-const EVENTS_PER_BLOCK: usize = 16;
-pub struct TraceBlock {
+/// The default block size. Each block is one leaf of an `IForestIndex`, so
+/// larger blocks mean smaller indexes but scanning more events per query.
+pub const EVENTS_PER_BLOCK: usize = 16;
+
+pub struct TraceBlock<E: Event = TraceEvent, const N: usize = EVENTS_PER_BLOCK> {
     pub len: u16,
-    events: [TraceEvent; EVENTS_PER_BLOCK],
+    events: [E; N],
 }
 
 impl TraceBlock {
     pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<E: Event, const N: usize> Default for TraceBlock<E, N> {
+    fn default() -> Self {
+        let () = Self::VALID_SIZE;
         Self {
             len: 0,
-            events: [NULL_EVENT; EVENTS_PER_BLOCK],
+            events: [E::NULL; N],
         }
     }
+}
+
+impl<E: Event, const N: usize> TraceBlock<E, N> {
+    /// Blocks count their events in a `u16`. Block and pool constructors
+    /// refer to this so that a bad `N` fails to compile rather than
+    /// corrupting blocks.
+    const VALID_SIZE: () = assert!(N > 0 && N <= u16::MAX as usize, "blocks must hold 1 to 65535 events");
 
     pub fn is_full(&self) -> bool {
-        self.len as usize == EVENTS_PER_BLOCK
+        self.len as usize == N
     }
 
-    pub fn push(&mut self, ev: TraceEvent) {
+    pub fn push(&mut self, ev: E) {
         assert!(!self.is_full());
         self.events[self.len as usize] = ev;
         self.len += 1;
     }
 
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
     #[inline]
-    pub fn events(&self) -> &[TraceEvent] {
+    pub fn events(&self) -> &[E] {
         &self.events[..self.len as usize]
     }
 
+    pub fn events_mut(&mut self) -> &mut [E] {
+        &mut self.events[..self.len as usize]
+    }
+
     /// Returns 0 if block is empty, `Track` has a useful invariant that
     /// blocks are never empty.
     #[inline]
     pub fn start_time(&self) -> Ns {
-        self.events[0].ts.unpack()
+        self.events[0].ts()
+    }
+}
+
+/// Same as `TraceBlock` but with each field in its own array, so that
+/// scanning one field, like searching timestamps, touches less memory.
+pub struct SoaBlock<const N: usize = EVENTS_PER_BLOCK> {
+    pub len: u16,
+    kinds: [u16; N],
+    ts: [PackedNs; N],
+    durs: [PackedNs; N],
+}
+
+impl<const N: usize> Default for SoaBlock<N> {
+    fn default() -> Self {
+        let () = TraceBlock::<TraceEvent, N>::VALID_SIZE;
+        Self {
+            len: 0,
+            kinds: [0; N],
+            ts: [PackedNs::new(0); N],
+            durs: [PackedNs::new(0); N],
+        }
+    }
+}
+
+impl<const N: usize> SoaBlock<N> {
+    pub fn is_full(&self) -> bool {
+        self.len as usize == N
+    }
+
+    pub fn push(&mut self, ev: TraceEvent) {
+        assert!(!self.is_full());
+        let i = self.len as usize;
+        self.kinds[i] = ev.kind;
+        self.ts[i] = ev.ts;
+        self.durs[i] = ev.dur;
+        self.len += 1;
+    }
+
+    #[inline]
+    pub fn event(&self, i: usize) -> TraceEvent {
+        TraceEvent { kind: self.kinds[i], ts: self.ts[i], dur: self.durs[i] }
+    }
+
+    #[inline]
+    pub fn ts(&self) -> &[PackedNs] {
+        &self.ts[..self.len as usize]
+    }
+
+    #[inline]
+    pub fn durs(&self) -> &[PackedNs] {
+        &self.durs[..self.len as usize]
+    }
+
+    pub(crate) fn to_block<E: Event>(&self, codec: Codec<E>) -> TraceBlock<E, N> {
+        let mut block = TraceBlock::default();
+        for i in 0..self.len as usize {
+            block.push((codec.unpack)(self.event(i)));
+        }
+        block
+    }
+
+    pub(crate) fn from_block<E: Event>(block: &TraceBlock<E, N>, codec: Codec<E>) -> Self {
+        let mut soa = Self::default();
+        for ev in block.events() {
+            soa.push((codec.pack)(ev));
+        }
+        soa
+    }
+
+    /// Returns 0 if block is empty, like `TraceBlock::start_time`
+    #[inline]
+    pub fn start_time(&self) -> Ns {
+        self.ts[0].unpack()
+    }
+}
+
+/// A view of a block from a `BlockPool`, whatever its layout, which may
+/// have been decoded on access.
+#[allow(clippy::large_enum_variant)] // Decoding onto the stack saves an allocation per block
+pub enum BlockRef<'a, E: Event = TraceEvent, const N: usize = EVENTS_PER_BLOCK> {
+    Borrowed(&'a TraceBlock<E, N>),
+    Decoded(TraceBlock<E, N>),
+    /// With the function turning the block's fields back into an `E`
+    Soa(&'a SoaBlock<N>, fn(TraceEvent) -> E),
+}
+
+impl<'a, E: Event, const N: usize> BlockRef<'a, E, N> {
+    #[inline]
+    pub fn len(&self) -> usize {
+        match self {
+            BlockRef::Borrowed(block) => block.len as usize,
+            BlockRef::Decoded(block) => block.len as usize,
+            BlockRef::Soa(block, _) => block.len as usize,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns 0 if block is empty
+    #[inline]
+    pub fn start_time(&self) -> Ns {
+        match self {
+            BlockRef::Borrowed(block) => block.start_time(),
+            BlockRef::Decoded(block) => block.start_time(),
+            BlockRef::Soa(block, _) => block.start_time(),
+        }
+    }
+
+    #[inline]
+    pub fn event(&self, i: usize) -> E {
+        match self {
+            BlockRef::Borrowed(block) => block.events()[i],
+            BlockRef::Decoded(block) => block.events()[i],
+            BlockRef::Soa(block, unpack) => unpack(block.event(i)),
+        }
+    }
+
+    #[inline]
+    pub fn ts(&self, i: usize) -> Ns {
+        match self {
+            BlockRef::Borrowed(block) => block.events()[i].ts(),
+            BlockRef::Decoded(block) => block.events()[i].ts(),
+            BlockRef::Soa(block, _) => block.ts()[i].unpack(),
+        }
+    }
+
+    #[inline]
+    pub fn dur(&self, i: usize) -> Ns {
+        match self {
+            BlockRef::Borrowed(block) => block.events()[i].dur(),
+            BlockRef::Decoded(block) => block.events()[i].dur(),
+            BlockRef::Soa(block, _) => block.durs()[i].unpack(),
+        }
+    }
+
+    pub fn last(&self) -> Option<E> {
+        self.len().checked_sub(1).map(|i| self.event(i))
+    }
+
+    pub fn events(&self) -> impl Iterator<Item=E> + '_ {
+        (0..self.len()).map(move |i| self.event(i))
+    }
+
+    /// Index of the first event starting at or after `ts`, or `len()` if
+    /// there isn't one.
+    #[inline]
+    pub fn partition_ts(&self, ts: Ns) -> usize {
+        match self {
+            BlockRef::Borrowed(block) => block.events().partition_point(|ev| ev.ts() < ts),
+            BlockRef::Decoded(block) => block.events().partition_point(|ev| ev.ts() < ts),
+            BlockRef::Soa(block, _) => block.ts().partition_point(|t| t.unpack() < ts),
+        }
+    }
+
+    /// Index of the longest event in `r`, the last one if there's a tie.
+    #[inline]
+    pub fn longest_in(&self, r: Range<usize>) -> Option<usize> {
+        fn longest<T>(items: &[T], start: usize, dur: impl Fn(&T) -> Ns) -> Option<usize> {
+            items.iter().enumerate().max_by_key(|(_, x)| dur(x)).map(|(i, _)| start + i)
+        }
+        let start = r.start;
+        match self {
+            BlockRef::Borrowed(block) => longest(&block.events()[r], start, |ev| ev.dur()),
+            BlockRef::Decoded(block) => longest(&block.events()[r], start, |ev| ev.dur()),
+            BlockRef::Soa(block, _) => longest(&block.durs()[r], start, |d| d.unpack()),
+        }
+    }
+}
+
+impl<'a, E: Event, const N: usize> IntoIterator for BlockRef<'a, E, N> {
+    type Item = E;
+    type IntoIter = BlockEvents<'a, E, N>;
+
+    fn into_iter(self) -> BlockEvents<'a, E, N> {
+        BlockEvents { block: self, i: 0 }
+    }
+}
+
+/// Iterates the events of a block by value, since a decoded block can't be
+/// borrowed from.
+pub struct BlockEvents<'a, E: Event = TraceEvent, const N: usize = EVENTS_PER_BLOCK> {
+    block: BlockRef<'a, E, N>,
+    i: usize,
+}
+
+impl<'a, E: Event, const N: usize> Iterator for BlockEvents<'a, E, N> {
+    type Item = E;
+
+    #[inline]
+    fn next(&mut self) -> Option<E> {
+        if self.i >= self.block.len() {
+            return None;
+        }
+        let ev = self.block.event(self.i);
+        self.i += 1;
+        Some(ev)
     }
 }
 
-pub struct BlockPool {
-    pub blocks: Vec<TraceBlock>,
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BlockFormat {
+    /// Blocks are stored as they are, 14 bytes per event
+    Raw,
+    /// Full blocks are delta and varint encoded, and decoded each time
+    /// they're accessed
+    Compressed,
+    /// Like `Raw` but each block stores kinds, timestamps and durations in
+    /// separate arrays
+    Soa,
+}
+
+enum Storage<E: Event, const N: usize> {
+    Raw(Vec<TraceBlock<E, N>>),
+    Compressed(CompressedBlocks<E, N>),
+    Soa(Vec<SoaBlock<N>>, Codec<E>),
+}
+
+/// Storage for the blocks of many tracks of events of type `E`. Use
+/// `BlockPool::<E, N>::default()` or `with_format` for other event types
+/// or block sizes other than `EVENTS_PER_BLOCK`. Only `PackableEvent`s can
+/// be stored in formats other than `BlockFormat::Raw`.
+pub struct BlockPool<E: Event = TraceEvent, const N: usize = EVENTS_PER_BLOCK> {
+    storage: Storage<E, N>,
+    /// Blocks no track uses anymore, handed out again before new ones
+    free: Vec<BlockIndex>,
 }
 
 impl BlockPool {
     pub fn new() -> Self {
-        BlockPool {
-            blocks: vec![],
+        Self::default()
+    }
+}
+
+impl<E: Event, const N: usize> Default for BlockPool<E, N> {
+    fn default() -> Self {
+        let () = TraceBlock::<E, N>::VALID_SIZE;
+        BlockPool { storage: Storage::Raw(vec![]), free: vec![] }
+    }
+}
+
+impl<E: PackableEvent, const N: usize> BlockPool<E, N> {
+    pub fn with_format(format: BlockFormat) -> Self {
+        let () = TraceBlock::<E, N>::VALID_SIZE;
+        let storage = match format {
+            BlockFormat::Raw => Storage::Raw(vec![]),
+            BlockFormat::Compressed => Storage::Compressed(CompressedBlocks::new(Codec::new())),
+            BlockFormat::Soa => Storage::Soa(vec![], Codec::new()),
+        };
+        BlockPool { storage, free: vec![] }
+    }
+}
+
+impl<E: Event, const N: usize> BlockPool<E, N> {
+    pub fn format(&self) -> BlockFormat {
+        match self.storage {
+            Storage::Raw(_) => BlockFormat::Raw,
+            Storage::Compressed(_) => BlockFormat::Compressed,
+            Storage::Soa(..) => BlockFormat::Soa,
         }
     }
 
+    /// Number of blocks allocated
+    pub fn len(&self) -> usize {
+        match &self.storage {
+            Storage::Raw(blocks) => blocks.len(),
+            Storage::Compressed(blocks) => blocks.len(),
+            Storage::Soa(blocks, _) => blocks.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many more blocks `alloc` can hand out.
+    pub fn available(&self) -> usize {
+        self.free.len() + (BlockIndex::MAX as usize + 1).saturating_sub(self.len())
+    }
+
     pub fn alloc(&mut self) -> BlockIndex {
-        let i = self.blocks.len();
-        self.blocks.push(TraceBlock::new());
-        i as BlockIndex
+        self.try_alloc().expect("block pool full")
+    }
+
+    pub fn try_alloc(&mut self) -> Result<BlockIndex> {
+        if let Some(i) = self.free.pop() {
+            return Ok(i);
+        }
+        let i = self.len();
+        if i > BlockIndex::MAX as usize {
+            return Err(Error::BlockIndexExhausted);
+        }
+        match &mut self.storage {
+            Storage::Raw(blocks) => blocks.push(TraceBlock::default()),
+            Storage::Compressed(blocks) => { blocks.alloc(); }
+            Storage::Soa(blocks, _) => blocks.push(SoaBlock::default()),
+        }
+        Ok(i as BlockIndex)
+    }
+
+    /// Empties a block no track uses anymore so `alloc` can reuse it.
+    pub fn free(&mut self, i: BlockIndex) {
+        self.modify(i, |block| block.clear());
+        self.free.push(i);
+    }
+
+    #[inline]
+    pub fn block(&self, i: BlockIndex) -> BlockRef<'_, E, N> {
+        match &self.storage {
+            Storage::Raw(blocks) => BlockRef::Borrowed(&blocks[i as usize]),
+            Storage::Compressed(blocks) => blocks.block(i),
+            Storage::Soa(blocks, codec) => BlockRef::Soa(&blocks[i as usize], codec.unpack),
+        }
+    }
+
+    /// Same as `block(i).start_time()` but doesn't need to decode the block.
+    #[inline]
+    pub fn start_time(&self, i: BlockIndex) -> Ns {
+        match &self.storage {
+            Storage::Raw(blocks) => blocks[i as usize].start_time(),
+            Storage::Compressed(blocks) => blocks.start_time(i),
+            Storage::Soa(blocks, _) => blocks[i as usize].start_time(),
+        }
+    }
+
+    pub fn is_full(&self, i: BlockIndex) -> bool {
+        match &self.storage {
+            Storage::Raw(blocks) => blocks[i as usize].is_full(),
+            Storage::Compressed(blocks) => blocks.is_full(i),
+            Storage::Soa(blocks, _) => blocks[i as usize].is_full(),
+        }
+    }
+
+    pub fn push(&mut self, i: BlockIndex, ev: E) {
+        match &mut self.storage {
+            Storage::Raw(blocks) => blocks[i as usize].push(ev),
+            Storage::Compressed(blocks) => blocks.push(i, ev),
+            Storage::Soa(blocks, codec) => blocks[i as usize].push((codec.pack)(&ev)),
+        }
+    }
+
+    /// Edits a block in place, which for compressed and SoA blocks means
+    /// converting it to a `TraceBlock` and back.
+    pub fn modify(&mut self, i: BlockIndex, f: impl FnOnce(&mut TraceBlock<E, N>)) {
+        match &mut self.storage {
+            Storage::Raw(blocks) => f(&mut blocks[i as usize]),
+            Storage::Compressed(blocks) => blocks.modify(i, f),
+            Storage::Soa(blocks, codec) => {
+                let soa = &mut blocks[i as usize];
+                let mut block = soa.to_block(*codec);
+                f(&mut block);
+                *soa = SoaBlock::from_block(&block, *codec);
+            }
+        }
+    }
+
+    /// Approximate bytes of memory used by the pool.
+    pub fn memory_usage(&self) -> usize {
+        match &self.storage {
+            Storage::Raw(blocks) => blocks.capacity() * mem::size_of::<TraceBlock<E, N>>(),
+            Storage::Compressed(blocks) => blocks.memory_usage(),
+            Storage::Soa(blocks, _) => blocks.capacity() * mem::size_of::<SoaBlock<N>>(),
+        }
     }
 }
 
-pub struct Track {
+/// The blocks of a track are in a `BlockPool` with the same event type and
+/// block size.
+pub struct Track<E: Event = TraceEvent, const N: usize = EVENTS_PER_BLOCK> {
     pub block_locs: Vec<BlockIndex>,
+    _event: PhantomData<E>,
 }
 
 impl Track {
     pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<E: Event, const N: usize> Default for Track<E, N> {
+    fn default() -> Self {
         Self {
-            block_locs: vec![]
+            block_locs: vec![],
+            _event: PhantomData,
         }
     }
+}
 
-    fn new_block(&mut self, pool: &mut BlockPool) -> BlockIndex {
-        let i = pool.alloc();
+impl<E: Event, const N: usize> Track<E, N> {
+    fn new_block(&mut self, pool: &mut BlockPool<E, N>) -> Result<BlockIndex> {
+        let i = pool.try_alloc()?;
         self.block_locs.push(i);
-        i
+        Ok(i)
     }
 
-    pub fn push(&mut self, pool: &mut BlockPool, ev: TraceEvent) {
+    /// Events must be pushed in timestamp order, which isn't checked, see
+    /// `try_push` for input that might be out of order.
+    pub fn push(&mut self, pool: &mut BlockPool<E, N>, ev: E) {
+        self.push_unchecked(pool, ev).expect("block pool full")
+    }
+
+    /// Like `push` but returns an error instead of corrupting the track if
+    /// `ev` starts before the last event.
+    pub fn try_push(&mut self, pool: &mut BlockPool<E, N>, ev: E) -> Result<()> {
+        if let Some(prev) = self.end_time(pool) {
+            let ts = ev.ts();
+            if ts < prev {
+                return Err(Error::NonMonotonic { prev, ts });
+            }
+        }
+        self.push_unchecked(pool, ev)
+    }
+
+    fn push_unchecked(&mut self, pool: &mut BlockPool<E, N>, ev: E) -> Result<()> {
         let last = match self.block_locs.last() {
-            None => self.new_block(pool),
-            Some(&i) if pool.blocks[i as usize].is_full() => self.new_block(pool),
+            None => self.new_block(pool)?,
+            Some(&i) if pool.is_full(i) => self.new_block(pool)?,
             Some(&i) => i
         };
-        pool.blocks[last as usize].push(ev)
+        pool.push(last, ev);
+        Ok(())
+    }
+
+    /// Replaces all the events on the track, which must already be sorted,
+    /// reusing its blocks and freeing any left over.
+    pub fn rewrite(&mut self, pool: &mut BlockPool<E, N>, events: &[E]) -> Result<()> {
+        let old_locs = std::mem::take(&mut self.block_locs);
+        for i in &old_locs {
+            pool.modify(*i, |block| block.clear());
+        }
+        let mut spare = old_locs.into_iter();
+        for ev in events {
+            let last = match self.block_locs.last() {
+                Some(&i) if !pool.is_full(i) => i,
+                _ => {
+                    let i = match spare.next() {
+                        Some(i) => i,
+                        None => pool.try_alloc()?,
+                    };
+                    self.block_locs.push(i);
+                    i
+                }
+            };
+            pool.push(last, *ev);
+        }
+        for i in spare {
+            pool.free(i);
+        }
+        Ok(())
+    }
+
+    pub fn start_time(&self, pool: &BlockPool<E, N>) -> Option<Ns> {
+        self.block_locs.first().map(|i| pool.start_time(*i))
+    }
+
+    pub fn end_time(&self, pool: &BlockPool<E, N>) -> Option<Ns> {
+        self.block_locs.last().and_then(|i| pool.block(*i).last().map(|x| x.ts()))
+    }
+
+    pub fn after_last_time(&self, pool: &BlockPool<E, N>) -> Option<Ns> {
+        self.block_locs.last().and_then(|i| pool.block(*i).last().map(|x| x.ts() + x.dur()))
+    }
+
+    pub fn events<'a>(&'a self, pool: &'a BlockPool<E, N>) -> impl Iterator<Item=E> + 'a {
+        self.block_locs.iter().flat_map(move |i| pool.block(*i))
     }
 
-    pub fn add_dummy_events(&mut self, pool: &mut BlockPool, rng: &Rng, n: usize) {
+    /// Index into `block_locs` of the first block that could contain an event
+    /// starting at or after `ts`.
+    pub fn block_containing(&self, pool: &BlockPool<E, N>, ts: Ns) -> usize {
+        self.block_locs
+            .partition_point(|i| pool.start_time(*i) < ts)
+            .saturating_sub(1)
+    }
+
+    /// Like `events` but starts at the first event with a timestamp of at
+    /// least `ts`, using a binary search to skip earlier blocks.
+    pub fn events_from<'a>(&'a self, pool: &'a BlockPool<E, N>, ts: Ns) -> impl Iterator<Item=E> + 'a {
+        let first = self.block_containing(pool, ts);
+        self.block_locs[first..].iter()
+            .flat_map(move |i| pool.block(*i))
+            .skip_while(move |ev| ev.ts() < ts)
+    }
+}
+
+impl<const N: usize> Track<TraceEvent, N> {
+    pub fn add_dummy_events(&mut self, pool: &mut BlockPool<TraceEvent, N>, rng: &Rng, n: usize) {
         let mut ts = 0;
         ts += rng.u64(..100_000);
         for _ in 0..n {
@@ -129,20 +692,4 @@ impl Track {
             ts += dur;
         }
     }
-
-    pub fn start_time(&self, pool: &BlockPool) -> Option<Ns> {
-        self.block_locs.get(0).map(|i| pool.blocks[*i as usize].start_time())
-    }
-
-    pub fn end_time(&self, pool: &BlockPool) -> Option<Ns> {
-        self.block_locs.last().and_then(|i| pool.blocks[*i as usize].events().last()).map(|x| x.ts.unpack())
-    }
-
-    pub fn after_last_time(&self, pool: &BlockPool) -> Option<Ns> {
-        self.block_locs.last().and_then(|i| pool.blocks[*i as usize].events().last()).map(|x| x.ts.unpack() + x.dur.unpack())
-    }
-
-    pub fn events<'a>(&'a self, pool: &'a BlockPool) -> impl Iterator<Item=&'a TraceEvent> + 'a {
-        self.block_locs.iter().flat_map(move |i| pool.blocks[*i as usize].events())
-    }
 }
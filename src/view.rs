@@ -0,0 +1,492 @@
+use crate::instant::InstantTrackInfo;
+use crate::meta::TrackMeta;
+use crate::trace::{Ns, PackedNs, TraceEvent};
+use crate::{aggregate_by_steps, Trace, TrackInfo};
+use std::ops::Range;
+
+pub const TRACK_HEIGHT: f64 = 30.0;
+/// Rows of an async track after the first, which are stacked under its label
+pub const LANE_HEIGHT: f64 = 15.0;
+
+/// Maps times in a view range to x positions `width` pixels across.
+pub struct ViewMap {
+    pub start: f64,
+    pub scale: f64,
+}
+
+impl ViewMap {
+    pub fn new(r: &Range<Ns>, width: f64) -> Self {
+        let len = r.end.saturating_sub(r.start).max(1);
+        Self { start: r.start as f64, scale: width.max(0.0) / (len as f64) }
+    }
+
+    pub fn to_x(&self, t: Ns) -> f64 {
+        ((t as f64) - self.start) * self.scale
+    }
+
+    /// The time at `x`, which is the start of the view if it has no width.
+    pub fn to_ns(&self, x: f64) -> f64 {
+        if self.scale == 0.0 {
+            return self.start;
+        }
+        self.start + (x / self.scale)
+    }
+}
+
+/// The narrowest a view can be zoomed in to.
+pub const MIN_VIEW_NS: Ns = 1;
+/// The widest a view can be zoomed out to, twice the longest a trace can
+/// be so that any trace fits with room to spare.
+pub const MAX_VIEW_NS: Ns = 2 * PackedNs::MAX;
+
+/// `view` zoomed by `factor`, where less than one zooms in, keeping the
+/// time at `at_x` pixels across in place. The view stays between
+/// `MIN_VIEW_NS` and `MAX_VIEW_NS` wide and never moves before time zero.
+pub fn zoom_view(view: &Range<Ns>, width: f64, factor: f64, at_x: f64) -> Range<Ns> {
+    if width <= 0.0 || !factor.is_finite() || factor <= 0.0 {
+        return view.clone();
+    }
+    let len = view.end.saturating_sub(view.start) as f64;
+    let new_len = (len * factor).clamp(MIN_VIEW_NS as f64, MAX_VIEW_NS as f64);
+    let at = ViewMap::new(view, width).to_ns(at_x);
+    let start = at - new_len * (at_x / width).clamp(0.0, 1.0);
+    // Float to int casts saturate, so this can't wrap below zero
+    let start = start.max(0.0) as Ns;
+    clamp_view(start, new_len as Ns)
+}
+
+/// `view` moved so that the time at x ends up at `x + dx`, stopping at
+/// time zero.
+pub fn pan_view(view: &Range<Ns>, width: f64, dx: f64) -> Range<Ns> {
+    if width <= 0.0 || !dx.is_finite() {
+        return view.clone();
+    }
+    let len = view.end.saturating_sub(view.start);
+    let start = (view.start as f64 - dx * len as f64 / width).max(0.0) as Ns;
+    clamp_view(start, len)
+}
+
+fn clamp_view(start: Ns, len: Ns) -> Range<Ns> {
+    let len = len.max(MIN_VIEW_NS);
+    let start = start.min(Ns::MAX - len);
+    start..start + len
+}
+
+/// Buckets the view range into power of two steps covering at least two
+/// pixels, so that the buckets stay put as the view pans and only change
+/// when zooming by a factor of two.
+pub struct ViewQuant {
+    pub time_step: Ns,
+}
+
+impl ViewQuant {
+    pub fn new(r: &Range<Ns>, width: f64) -> Self {
+        let ns_per_px = r.end.saturating_sub(r.start) / (width.max(1.0) as u64);
+        let min_event_px = 2;
+        let step = ns_per_px.saturating_mul(min_event_px);
+        let step = step.saturating_add(1).checked_next_power_of_two().unwrap_or(1 << 63);
+        Self { time_step: step }
+    }
+
+    pub fn round_down(&self, x: Ns) -> Ns {
+        x - (x % self.time_step)
+    }
+
+    /// `r` widened out to whole steps, stopping at `Ns::MAX`.
+    pub fn quantize(&self, r: &Range<Ns>) -> Range<Ns> {
+        self.round_down(r.start)..self.round_down(r.end).saturating_add(self.time_step)
+    }
+}
+
+/// Something to draw in a track's row, with x in pixels from the left of
+/// the view.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DrawItem {
+    /// An event longer than a bucket, or a bucket standing in for the events
+    /// starting in it shaded by how busy it is from 0 to 1
+    Span { x0: f64, x1: f64, kind: u16, density: f64 },
+    /// A tick for one instant, or a bar from the first to the last of
+    /// several in the same bucket
+    Instants { x0: f64, x1: f64, count: usize },
+}
+
+/// Which track a row shows, as an index into `Trace::tracks` or
+/// `Trace::instant_tracks`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RowTrack {
+    Track(usize),
+    Instant(usize),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Row {
+    pub track: RowTrack,
+    pub y: f64,
+    pub height: f64,
+    /// Only the first lane of an async track is labelled
+    pub label: Option<String>,
+    /// `TrackMeta::color`, used instead of the usual colors if set
+    pub color: Option<[u8; 3]>,
+}
+
+/// Lays out the rows of the tracks top to bottom, grouped by process with
+/// each process's instant tracks first.
+pub fn rows(trace: &Trace) -> Vec<Row> {
+    let mut out = vec![];
+    let mut y = 0.0;
+    for process in trace.processes() {
+        let label = |meta: &TrackMeta| match &process.name {
+            Some(process_name) => format!("{} / {}", process_name, meta.name),
+            None => meta.name.clone(),
+        };
+        for &i in &process.instant_tracks {
+            let meta = &trace.instant_tracks[i].meta;
+            let label = Some(label(meta));
+            out.push(Row { track: RowTrack::Instant(i), y, height: TRACK_HEIGHT, label, color: meta.color });
+            y += TRACK_HEIGHT;
+        }
+        for &i in &process.tracks {
+            let meta = &trace.tracks[i].meta;
+            let (height, label) = match meta.lane {
+                Some(lane) if lane > 0 => (LANE_HEIGHT, None),
+                _ => (TRACK_HEIGHT, Some(label(meta))),
+            };
+            out.push(Row { track: RowTrack::Track(i), y, height, label, color: meta.color });
+            y += height;
+        }
+    }
+    out
+}
+
+/// A time or duration in the largest unit it's at least one of.
+pub fn format_time(ns: Ns) -> String {
+    match ns {
+        0..=999 => format!("{}ns", ns),
+        1_000..=999_999 => format!("{:.2}us", ns as f64 / 1e3),
+        1_000_000..=999_999_999 => format!("{:.2}ms", ns as f64 / 1e6),
+        _ => format!("{:.3}s", ns as f64 / 1e9),
+    }
+}
+
+/// A tick on the time ruler.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tick {
+    pub x: f64,
+    pub time: Ns,
+    pub label: String,
+}
+
+/// The smallest of 1, 2 or 5 times a power of ten nanoseconds which is at
+/// least `min_px` pixels wide, or `None` for a view with no width.
+pub fn tick_interval(map: &ViewMap, min_px: f64) -> Option<Ns> {
+    if map.scale <= 0.0 || !map.scale.is_finite() {
+        return None;
+    }
+    let min_ns = min_px / map.scale;
+    let mut power: Ns = 1;
+    loop {
+        for &m in &[1, 2, 5] {
+            let interval = power.checked_mul(m)?;
+            if interval as f64 >= min_ns {
+                return Some(interval);
+            }
+        }
+        power = power.checked_mul(10)?;
+    }
+}
+
+/// Formats times which are multiples of `interval` in the unit that keeps
+/// every label short, with just enough decimals for the interval, so that
+/// all the labels of a ruler look alike.
+fn tick_format(interval: Ns) -> impl Fn(i128) -> String {
+    let (unit, name) = [(1_000_000_000, "s"), (1_000_000, "ms"), (1_000, "us"), (1, "ns")]
+        .iter()
+        .copied()
+        .find(|&(unit, _)| unit <= 100 * interval as i128)
+        .unwrap_or((1, "ns"));
+    let mut decimals = 0;
+    let mut scaled = interval as i128;
+    while scaled < unit {
+        scaled *= 10;
+        decimals += 1;
+    }
+    move |ns: i128| {
+        let sign = if ns < 0 { "-" } else { "" };
+        let (whole, frac) = (ns.abs() / unit, ns.abs() % unit);
+        if decimals == 0 {
+            format!("{}{}{}", sign, whole, name)
+        } else {
+            let frac = frac / (unit / 10i128.pow(decimals));
+            format!("{}{}.{:0width$}{}", sign, whole, frac, name, width = decimals as usize)
+        }
+    }
+}
+
+/// Ticks for a ruler over `view`, at round intervals at least `min_px`
+/// apart and labelled with the time since `origin`, like the start of the
+/// trace. Ticks fall on multiples of the interval from `origin`, so they
+/// stay put while panning.
+pub fn ruler_ticks(view: &Range<Ns>, width: f64, origin: Ns, min_px: f64) -> Vec<Tick> {
+    let map = ViewMap::new(view, width);
+    // At least a pixel apart, or a tiny `min_px` would mean a tick per ns
+    let interval = match tick_interval(&map, min_px.max(1.0)) {
+        Some(interval) if view.start < view.end => interval,
+        _ => return vec![],
+    };
+    let format = tick_format(interval);
+    let (step, origin) = (interval as i128, origin as i128);
+    // The first multiple of the interval at or after the start of the view
+    let offset = view.start as i128 - origin;
+    let mut rel = offset.div_euclid(step) * step;
+    if rel < offset {
+        rel += step;
+    }
+    let mut out = vec![];
+    while origin + rel <= view.end as i128 {
+        let time = (origin + rel) as Ns;
+        out.push(Tick { x: map.to_x(time), time, label: format(rel) });
+        rel += step;
+    }
+    out
+}
+
+pub fn kind_color(kind: u16) -> [u8; 3] {
+    [0x00, 0x00, (kind % 250) as u8]
+}
+
+pub const INSTANT_COLOR: [u8; 3] = [0x80, 0x00, 0x80];
+
+/// The color of an item on `row`, the track's own color if it has one.
+pub fn item_color(row: &Row, item: &DrawItem) -> [u8; 3] {
+    match (row.color, item) {
+        (Some(color), _) => color,
+        (None, DrawItem::Span { kind, .. }) => kind_color(*kind),
+        (None, DrawItem::Instants { .. }) => INSTANT_COLOR,
+    }
+}
+
+/// What to draw for `track` with `view` spread over `width` pixels. Events
+/// longer than a bucket are drawn at their real extent, while shorter ones
+/// are drawn as the whole bucket they start in, using the longest event in
+/// the bucket for its kind.
+pub fn track_items(trace: &Trace, track: &TrackInfo, view: &Range<Ns>, width: f64) -> Vec<DrawItem> {
+    if width <= 0.0 || view.start >= view.end {
+        return vec![];
+    }
+    let map = ViewMap::new(view, width);
+    let quant = ViewQuant::new(view, width);
+    let quantized = quant.quantize(view);
+    let visible = aggregate_by_steps(&trace.pool, &track.track.block_locs, &track.zoom_index, quantized.clone(), quant.time_step);
+    let busy = track.busy_fractions(&trace.pool, quantized.clone(), quant.time_step);
+    bucket_items(&map, &quant, &quantized, visible.iter().map(|x| x.0), &busy)
+}
+
+/// Turns the longest event before `quantized` followed by the longest
+/// event and busy fraction of each of its buckets into draw items.
+pub(crate) fn bucket_items(
+    map: &ViewMap,
+    quant: &ViewQuant,
+    quantized: &Range<Ns>,
+    longest: impl Iterator<Item = Option<TraceEvent>>,
+    busy: &[f64],
+) -> Vec<DrawItem> {
+    let mut out = vec![];
+    for ev in longest.flatten() {
+        let ts = ev.ts.unpack();
+        let dur = ev.dur.unpack();
+        let (start, end, density) = if dur > quant.time_step {
+            (ts, ts + dur, 1.0)
+        } else {
+            // Shade buckets standing in for lots of events by how busy they are
+            let start = quant.round_down(ts);
+            let density = start.checked_sub(quantized.start)
+                .and_then(|t| busy.get((t / quant.time_step) as usize))
+                .copied()
+                .unwrap_or(1.0);
+            (start, start.saturating_add(quant.time_step), density)
+        };
+        out.push(DrawItem::Span { x0: map.to_x(start), x1: map.to_x(end), kind: ev.kind, density });
+    }
+    out
+}
+
+/// What to draw for an instant track, with ticks at least a pixel wide.
+pub fn instant_items(trace: &Trace, track: &InstantTrackInfo, view: &Range<Ns>, width: f64) -> Vec<DrawItem> {
+    if width <= 0.0 || view.start >= view.end {
+        return vec![];
+    }
+    let map = ViewMap::new(view, width);
+    let quant = ViewQuant::new(view, width);
+    let quantized = quant.quantize(view);
+    let mut out = vec![];
+    for sum in track.summaries(&trace.instant_pool, quantized, quant.time_step) {
+        if let (Some(first), Some(last)) = (sum.first, sum.last) {
+            let x0 = map.to_x(first.ts.unpack());
+            let x1 = map.to_x(last.ts.unpack()).max(x0 + 1.0);
+            out.push(DrawItem::Instants { x0, x1, count: sum.count });
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instant::InstantEvent;
+    use crate::trace::Track;
+
+    #[test]
+    fn view_map() {
+        let map = ViewMap::new(&(1000..3000), 200.0);
+        assert_eq!(map.to_x(1000), 0.0);
+        assert_eq!(map.to_x(2000), 100.0);
+        assert_eq!(map.to_ns(150.0), 2500.0);
+
+        // Degenerate views don't divide by zero
+        let map = ViewMap::new(&(1000..3000), 0.0);
+        assert_eq!(map.to_x(2000), 0.0);
+        assert_eq!(map.to_ns(10.0), 1000.0);
+        let map = ViewMap::new(&(1000..1000), 100.0);
+        assert!(map.to_x(1001).is_finite());
+    }
+
+    #[test]
+    fn view_quant() {
+        for &(len, width) in &[(1_000_000u64, 1000.0), (1_000_001, 1000.0), (7, 1000.0), (1 << 40, 3.0), (5000, 0.0), (0, 10.0)] {
+            let r = 12_345..12_345 + len;
+            let quant = ViewQuant::new(&r, width);
+            assert!(quant.time_step.is_power_of_two());
+            assert!(quant.time_step > len / (width.max(1.0) as u64) * 2);
+            let q = quant.quantize(&r);
+            assert!(q.start <= r.start && q.end > r.end);
+            assert_eq!(q.start % quant.time_step, 0);
+        }
+        assert_eq!(ViewQuant::new(&(0..u64::MAX), 1.0).time_step, 1 << 63);
+        // The last step is cut short at the end of time
+        let quant = ViewQuant::new(&(1 << 40..u64::MAX), 1000.0);
+        assert_eq!(quant.quantize(&(1 << 40..u64::MAX)), 0..u64::MAX);
+        let mut trace = trace_with(&[(100, 500)]);
+        trace.add_instant_track(TrackMeta::default(), vec![InstantEvent::new(0, 50).unwrap()]).unwrap();
+        assert_eq!(track_items(&trace, &trace.tracks[0], &(0..u64::MAX), 1000.0).len(), 1);
+        assert_eq!(instant_items(&trace, &trace.instant_tracks[0], &(0..u64::MAX), 1000.0).len(), 1);
+    }
+
+    #[test]
+    fn zoom_and_pan() {
+        // Zooming keeps the time under the cursor in place
+        assert_eq!(zoom_view(&(1000..2000), 100.0, 0.5, 50.0), 1250..1750);
+        assert_eq!(zoom_view(&(1000..2000), 100.0, 2.0, 0.0), 1000..3000);
+        // but can't go below a nanosecond or before zero
+        assert_eq!(zoom_view(&(1000..1001), 100.0, 0.01, 50.0), 1000..1001);
+        assert_eq!(zoom_view(&(1000..1000), 100.0, 0.5, 0.0), 1000..1001);
+        assert_eq!(zoom_view(&(100..200), 100.0, 10.0, 50.0), 0..1000);
+        // or wider than `MAX_VIEW_NS`
+        assert_eq!(zoom_view(&(0..u64::MAX), 100.0, 10.0, 0.0), 0..MAX_VIEW_NS);
+        let trace = trace_with(&[(100, 500)]);
+        let mut view = 0..1000;
+        for _ in 0..400 {
+            view = zoom_view(&view, 100.0, 1.25, 50.0);
+        }
+        assert_eq!(view.end - view.start, MAX_VIEW_NS);
+        assert_eq!(track_items(&trace, &trace.tracks[0], &view, 100.0).len(), 1);
+        // Nonsense leaves the view alone
+        assert_eq!(zoom_view(&(100..200), 0.0, 0.5, 0.0), 100..200);
+        assert_eq!(zoom_view(&(100..200), 100.0, f64::NAN, 0.0), 100..200);
+
+        // Dragging right moves the view back in time
+        assert_eq!(pan_view(&(1000..2000), 100.0, 10.0), 900..1900);
+        assert_eq!(pan_view(&(1000..2000), 100.0, -10.0), 1100..2100);
+        assert_eq!(pan_view(&(1000..2000), 100.0, 500.0), 0..1000);
+        assert_eq!(pan_view(&(u64::MAX - 10..u64::MAX), 100.0, -1000.0), u64::MAX - 10..u64::MAX);
+    }
+
+    #[test]
+    fn ruler() {
+        let interval = |len: Ns, width: f64| tick_interval(&ViewMap::new(&(0..len), width), 50.0);
+        // 50px at 10ns per pixel is 500ns
+        assert_eq!(interval(10_000, 1000.0), Some(500));
+        assert_eq!(interval(10_001, 1000.0), Some(1000));
+        assert_eq!(interval(4_000, 1000.0), Some(200));
+        assert_eq!(interval(1, 1000.0), Some(1));
+        assert_eq!(interval(u64::MAX, 1.0), None);
+        assert_eq!(interval(1000, 0.0), None);
+
+        let labels = |view: Range<Ns>, origin| {
+            ruler_ticks(&view, 1000.0, origin, 50.0).into_iter().map(|t| t.label).collect::<Vec<_>>()
+        };
+        assert_eq!(labels(1000..1100, 1000)[..4], ["0ns", "5ns", "10ns", "15ns"]);
+        // Fractions of a bigger unit once the interval is a tenth of it
+        assert_eq!(labels(1000..21_000, 1000)[..3], ["0us", "1us", "2us"]);
+        assert_eq!(labels(1000..11_000, 1000)[..3], ["0.0us", "0.5us", "1.0us"]);
+        assert_eq!(labels(3_000_000..3_004_000, 1000)[..3], ["2999.0us", "2999.2us", "2999.4us"]);
+        assert_eq!(labels(5_000_000_000..25_000_000_000, 0)[..3], ["5s", "6s", "7s"]);
+        // Before the origin
+        assert_eq!(labels(0..10_000, 5000)[..3], ["-5.0us", "-4.5us", "-4.0us"]);
+
+        // Ticks stay on multiples of the interval from the origin
+        let ticks = ruler_ticks(&(1234..11_234), 1000.0, 1000, 50.0);
+        assert_eq!(ticks[0].time, 1500);
+        assert!((ticks[0].x - 26.6).abs() < 1e-9);
+        assert!(ticks.iter().all(|t| (t.time - 1000) % 500 == 0 && t.time <= 11_234));
+        assert_eq!(ticks.len(), 20);
+        assert!(ruler_ticks(&(10..10), 1000.0, 0, 50.0).is_empty());
+        // No spacing still means ticks at least a pixel apart
+        assert_eq!(ruler_ticks(&(0..1_000_000), 100.0, 0, 0.0).len(), 101);
+        assert_eq!(ruler_ticks(&(0..1_000_000), 100.0, 0, -5.0).len(), 101);
+    }
+
+    fn trace_with(events: &[(Ns, Ns)]) -> Trace {
+        let mut trace = Trace::new();
+        let mut track = Track::new();
+        for &(ts, dur) in events {
+            track.push(&mut trace.pool, TraceEvent::try_new(1, ts, dur).unwrap());
+        }
+        trace.tracks.push(TrackInfo::new(track, &trace.pool));
+        trace
+    }
+
+    #[test]
+    fn spans_and_buckets() {
+        // 8ns per pixel makes a 32ns step
+        let trace = trace_with(&[(100, 500), (700, 3), (705, 3), (720, 3)]);
+        let items = track_items(&trace, &trace.tracks[0], &(0..1024), 128.0);
+        assert_eq!(items, vec![
+            DrawItem::Span { x0: 12.5, x1: 75.0, kind: 1, density: 1.0 },
+            DrawItem::Span { x0: 84.0, x1: 88.0, kind: 1, density: 3.0 / 32.0 },
+            DrawItem::Span { x0: 88.0, x1: 92.0, kind: 1, density: 6.0 / 32.0 },
+        ]);
+    }
+
+    #[test]
+    fn degenerate_views() {
+        let mut trace = trace_with(&[(100, 500)]);
+        trace.add_instant_track(TrackMeta::default(), vec![InstantEvent::new(0, 50).unwrap()]).unwrap();
+        for (view, width) in [(0..1000, 0.0), (0..1000, -5.0), (500..500, 100.0), (Range { start: 600, end: 500 }, 100.0)] {
+            assert!(track_items(&trace, &trace.tracks[0], &view, width).is_empty());
+            assert!(instant_items(&trace, &trace.instant_tracks[0], &view, width).is_empty());
+        }
+        // A view narrower than a nanosecond per pixel
+        let items = track_items(&trace, &trace.tracks[0], &(100..110), 1000.0);
+        assert_eq!(items, vec![DrawItem::Span { x0: 0.0, x1: 50_000.0, kind: 1, density: 1.0 }]);
+        let items = instant_items(&trace, &trace.instant_tracks[0], &(0..128), 2.0);
+        assert_eq!(items, vec![DrawItem::Instants { x0: 0.78125, x1: 1.78125, count: 1 }]);
+    }
+
+    #[test]
+    fn row_layout() {
+        let mut trace = Trace::demo_trace(2, 10);
+        let meta = TrackMeta { name: "net".into(), pid: Some(1), process_name: Some("app".into()), ..Default::default() };
+        let spans = vec![TraceEvent::try_new(0, 0, 10).unwrap(), TraceEvent::try_new(0, 5, 10).unwrap()];
+        trace.add_async_track(meta.clone(), spans).unwrap();
+        trace.add_instant_track(meta, vec![]).unwrap();
+        let rows = rows(&trace);
+        let summary = rows.iter().map(|r| (r.track, r.y, r.label.as_deref())).collect::<Vec<_>>();
+        assert_eq!(summary, vec![
+            (RowTrack::Instant(0), 0.0, Some("app / net")),
+            (RowTrack::Track(2), 30.0, Some("app / net")),
+            (RowTrack::Track(3), 60.0, None),
+            (RowTrack::Track(0), 75.0, Some("Track 0")),
+            (RowTrack::Track(1), 105.0, Some("Track 1")),
+        ]);
+    }
+}
@@ -0,0 +1,280 @@
+use crate::cache::BucketCache;
+use crate::progressive::ProgressiveView;
+use crate::trace::Ns;
+use crate::view::{DrawItem, RowTrack};
+use crate::Trace;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How long a worker queries before sending what it has, so the viewer
+/// sharpens gradually rather than all at once
+const BATCH_TIME: Duration = Duration::from_millis(8);
+/// Bucket cache for each worker
+const CACHE_BUDGET: usize = 32 << 20;
+
+/// The draw items for every row of a view, as far as they've been computed.
+#[derive(Clone, Debug)]
+pub struct RenderList {
+    pub generation: u64,
+    pub range: Range<Ns>,
+    pub width: f64,
+    pub rows: Vec<RowTrack>,
+    /// Items for each of `rows`, and whether they're at full detail
+    pub items: Vec<Option<(Vec<DrawItem>, bool)>>,
+}
+
+impl RenderList {
+    fn new(generation: u64, range: Range<Ns>, width: f64, rows: Vec<RowTrack>) -> Self {
+        let items = vec![None; rows.len()];
+        RenderList { generation, range, width, rows, items }
+    }
+
+    pub fn items_for(&self, track: RowTrack) -> Option<&[DrawItem]> {
+        let i = self.rows.iter().position(|&r| r == track)?;
+        self.items[i].as_ref().map(|(items, _)| &items[..])
+    }
+
+    /// Whether every row has at least a rough result.
+    pub fn is_usable(&self) -> bool {
+        self.items.iter().all(Option::is_some)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.items.iter().all(|i| matches!(i, Some((_, true))))
+    }
+
+    /// Scale and offset taking x positions in this list to the same times
+    /// in another view, for drawing an old result while a new one is
+    /// computed.
+    pub fn transform_to(&self, range: &Range<Ns>, width: f64) -> (f64, f64) {
+        let old_len = self.range.end.saturating_sub(self.range.start).max(1) as f64;
+        let new_len = range.end.saturating_sub(range.start).max(1) as f64;
+        let px_per_ns = width / new_len;
+        let scale = old_len / self.width.max(f64::MIN_POSITIVE) * px_per_ns;
+        let offset = (self.range.start as f64 - range.start as f64) * px_per_ns;
+        (scale, offset)
+    }
+}
+
+struct Job {
+    generation: u64,
+    range: Range<Ns>,
+    width: f64,
+    /// Rows for this worker, with their index in the request
+    rows: Vec<(usize, RowTrack)>,
+}
+
+struct Update {
+    generation: u64,
+    rows: Vec<(usize, Vec<DrawItem>, bool)>,
+}
+
+/// Runs view queries on a pool of threads so the UI thread never waits on
+/// them. Each request splits its rows across the workers, which send back
+/// progressively refined results. Requests supersede older ones: workers
+/// drop stale jobs and stop in the middle of one as soon as a newer
+/// request comes in.
+///
+/// `latest` gives the newest result with something for every row, which
+/// may be for an older view until the current one has been roughed in.
+pub struct QueryWorker {
+    jobs: Vec<Sender<Job>>,
+    updates: Receiver<Update>,
+    generation: Arc<AtomicU64>,
+    threads: Vec<JoinHandle<()>>,
+    shown: Option<RenderList>,
+    pending: Option<RenderList>,
+}
+
+fn run(trace: Arc<Trace>, generation: Arc<AtomicU64>, jobs: Receiver<Job>, updates: Sender<Update>, notify: Arc<dyn Fn() + Send + Sync>) {
+    let mut cache = BucketCache::new(CACHE_BUDGET);
+    while let Ok(mut job) = jobs.recv() {
+        // Skip to the newest job
+        while let Ok(newer) = jobs.try_recv() {
+            job = newer;
+        }
+        let current = || generation.load(Ordering::Relaxed) == job.generation;
+        let tracks = job.rows.iter().map(|r| r.1).collect();
+        let mut progress = ProgressiveView::new(job.range.clone(), job.width, tracks);
+        let mut batch = vec![];
+        let mut batch_start = Instant::now();
+        while current() {
+            let row = progress.step(&trace, &mut cache);
+            if let Some(row) = row {
+                let items = progress.items(row).unwrap_or(&[]).to_vec();
+                batch.push((job.rows[row].0, items, progress.is_row_done(row)));
+            }
+            if (row.is_none() || batch_start.elapsed() >= BATCH_TIME) && !batch.is_empty() {
+                let update = Update { generation: job.generation, rows: std::mem::take(&mut batch) };
+                if updates.send(update).is_err() {
+                    return;
+                }
+                notify();
+                batch_start = Instant::now();
+            }
+            if row.is_none() {
+                break;
+            }
+        }
+    }
+}
+
+impl QueryWorker {
+    /// Starts a worker per core, up to `max_threads`. `notify` is called
+    /// from a worker thread whenever there are new results to `poll` for.
+    pub fn new(trace: Arc<Trace>, max_threads: usize, notify: impl Fn() + Send + Sync + 'static) -> Self {
+        let notify: Arc<dyn Fn() + Send + Sync> = Arc::new(notify);
+        let generation = Arc::new(AtomicU64::new(0));
+        let (update_tx, updates) = mpsc::channel();
+        let threads = thread::available_parallelism().map_or(1, |n| n.get()).min(max_threads).max(1);
+        let mut jobs = vec![];
+        let mut handles = vec![];
+        for _ in 0..threads {
+            let (tx, rx) = mpsc::channel();
+            jobs.push(tx);
+            let (trace, generation, updates, notify) = (trace.clone(), generation.clone(), update_tx.clone(), notify.clone());
+            handles.push(thread::spawn(move || run(trace, generation, rx, updates, notify)));
+        }
+        QueryWorker { jobs, updates, generation, threads: handles, shown: None, pending: None }
+    }
+
+    /// Asks for the items of `rows` in a view, unless that's the view
+    /// already being worked on or shown.
+    pub fn request(&mut self, range: Range<Ns>, width: f64, rows: Vec<RowTrack>) {
+        let same = |list: &Option<RenderList>| {
+            list.as_ref().is_some_and(|l| l.range == range && l.width == width && l.rows == rows)
+        };
+        if same(&self.pending) || (self.pending.is_none() && same(&self.shown)) {
+            return;
+        }
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
+        let n = self.jobs.len();
+        for (w, jobs) in self.jobs.iter().enumerate() {
+            let rows = rows.iter().copied().enumerate().skip(w).step_by(n).collect::<Vec<_>>();
+            let job = Job { generation, range: range.clone(), width, rows };
+            // A worker can only have gone if it panicked, which `Drop` reports
+            let _ = jobs.send(job);
+        }
+        self.pending = Some(RenderList::new(generation, range, width, rows));
+    }
+
+    /// Takes in results from the workers, returning whether anything new
+    /// arrived for the list `latest` returns.
+    pub fn poll(&mut self) -> bool {
+        let mut changed = false;
+        while let Ok(update) = self.updates.try_recv() {
+            let is_for = |list: &Option<RenderList>| list.as_ref().is_some_and(|l| l.generation == update.generation);
+            let list = if is_for(&self.shown) {
+                changed = true;
+                &mut self.shown
+            } else if is_for(&self.pending) {
+                &mut self.pending
+            } else {
+                // Superseded
+                continue;
+            };
+            let list = list.as_mut().unwrap();
+            for (i, items, done) in update.rows {
+                list.items[i] = Some((items, done));
+            }
+        }
+        if self.pending.as_ref().is_some_and(RenderList::is_usable) {
+            self.shown = self.pending.take();
+            changed = true;
+        }
+        changed
+    }
+
+    /// The newest result that has something for every row.
+    pub fn latest(&self) -> Option<&RenderList> {
+        self.shown.as_ref()
+    }
+
+    /// Whether the workers are still on the last request.
+    pub fn is_busy(&self) -> bool {
+        self.pending.is_some() || self.shown.as_ref().is_some_and(|l| !l.is_complete())
+    }
+}
+
+impl Drop for QueryWorker {
+    fn drop(&mut self) {
+        // Cancel whatever's running and close the job channels so the
+        // workers exit
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        self.jobs.clear();
+        for t in self.threads.drain(..) {
+            if t.join().is_err() && !thread::panicking() {
+                panic!("query worker panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::view;
+    use std::sync::atomic::AtomicUsize;
+
+    fn wait_until_complete(worker: &mut QueryWorker) {
+        let start = Instant::now();
+        while worker.is_busy() {
+            assert!(start.elapsed() < Duration::from_secs(60), "worker stuck");
+            worker.poll();
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn queries_in_background() {
+        let trace = Arc::new(Trace::demo_trace(6, 20_000));
+        let bounds = trace.time_bounds().unwrap();
+        let notified = Arc::new(AtomicUsize::new(0));
+        let n = notified.clone();
+        let mut worker = QueryWorker::new(trace.clone(), 3, move || {
+            n.fetch_add(1, Ordering::Relaxed);
+        });
+        let rows = (0..6).map(RowTrack::Track).collect::<Vec<_>>();
+
+        // A request superseded before anything is polled is never shown
+        let stale = bounds.start..bounds.start + 1000;
+        worker.request(stale.clone(), 300.0, rows.clone());
+        worker.request(bounds.clone(), 300.0, rows.clone());
+        wait_until_complete(&mut worker);
+        let list = worker.latest().unwrap();
+        assert_eq!(list.range, bounds);
+        assert!(list.is_complete());
+        for i in 0..6 {
+            let expected = view::track_items(&trace, &trace.tracks[i], &bounds, 300.0);
+            assert_eq!(list.items_for(RowTrack::Track(i)).unwrap(), &expected[..]);
+        }
+        assert!(notified.load(Ordering::Relaxed) > 0);
+
+        // Asking again for the same view doesn't start another request
+        let generation = list.generation;
+        worker.request(bounds.clone(), 300.0, rows.clone());
+        assert!(!worker.is_busy());
+
+        // The old result stays until the new one is usable
+        let half = bounds.start..(bounds.start + bounds.end) / 2;
+        worker.request(half.clone(), 300.0, rows);
+        assert_eq!(worker.latest().unwrap().generation, generation);
+        wait_until_complete(&mut worker);
+        assert_eq!(worker.latest().unwrap().range, half);
+    }
+
+    #[test]
+    fn transform() {
+        let list = RenderList::new(1, 1000..2000, 100.0, vec![]);
+        // Same view
+        assert_eq!(list.transform_to(&(1000..2000), 100.0), (1.0, 0.0));
+        // Panned right by half, so old x=50 is new x=0
+        assert_eq!(list.transform_to(&(1500..2500), 100.0), (1.0, -50.0));
+        // Zoomed in 2x on the start and twice as wide
+        assert_eq!(list.transform_to(&(1000..1500), 200.0), (4.0, 0.0));
+    }
+}
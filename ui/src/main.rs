@@ -2,98 +2,183 @@ use druid::kurbo::{Size};
 use druid::piet::{FontFamily, ImageFormat, InterpolationMode};
 use druid::widget::prelude::*;
 use druid::{
-    Affine, AppLauncher, Color, FontDescriptor, LocalizedString, Point, Rect, TextLayout,
-    WindowDesc,
+    Affine, AppLauncher, Color, ExtEventSink, FontDescriptor, KbKey, LocalizedString, Point,
+    Rect, Selector, Target, TextLayout, WindowDesc,
 };
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::ops::{Deref, Range};
-use std::u64;
 
 use gigatrace::trace::Ns;
-use gigatrace::index::LongestEvent;
-use gigatrace::{Trace, TrackInfo, self};
+use gigatrace::hit::HitEvent;
+use gigatrace::instant::InstantEvent;
+use gigatrace::json::Json;
+use gigatrace::meta::TrackMeta;
+use gigatrace::view::{self, DrawItem, Row, RowTrack, ViewMap};
+use gigatrace::worker::QueryWorker;
+use gigatrace::Trace;
 
-struct ViewMap {
-    start: f64,
-    scale: f64,
+/// Sent by the query workers when they have results to poll for
+const QUERY_DONE: Selector = Selector::new("gigatrace.query-done");
+const MAX_QUERY_THREADS: usize = 4;
+/// How far a key press pans, as a fraction of the width
+const KEY_PAN: f64 = 0.1;
+/// How much a key press zooms in
+const KEY_ZOOM: f64 = 0.8;
+/// How far either side of the cursor an event can be and still be picked
+const HIT_SLOP_PX: f64 = 2.0;
+/// How far the mouse can move between press and release to count as a click
+const CLICK_SLOP_PX: f64 = 3.0;
+const PANEL_WIDTH: f64 = 300.0;
+const LINE_HEIGHT: f64 = 18.0;
+const RULER_HEIGHT: f64 = 24.0;
+/// Ruler labels need about this much room
+const MIN_TICK_PX: f64 = 80.0;
+
+struct TimelineWidget {
+    view_range: Range<Ns>,
+    worker: QueryWorker,
+    /// Where the mouse was last, to zoom around with the keyboard
+    mouse_x: Option<f64>,
+    /// Where the mouse was last while dragging
+    drag_x: Option<f64>,
+    /// Where the mouse was pressed, to tell clicks from drags
+    press_pos: Option<Point>,
+    /// Events under the mouse and last clicked on, with their track
+    hovered: Option<(usize, HitEvent)>,
+    selected: Option<(usize, HitEvent)>,
 }
 
-impl ViewMap {
-    pub fn new(r: &Range<Ns>, width: f64) -> Self {
-        Self { start: r.start as f64, scale: width/((r.end - r.start) as f64) }
+impl TimelineWidget {
+    fn paint_track(ctx: &mut PaintCtx, row: &Row, items: &[DrawItem], size: Size) {
+        for item in items {
+            if let DrawItem::Span { x0, x1, density, .. } = *item {
+                let rect = Rect::new(x0, 0.0, x1, size.height);
+                let [r, g, b] = view::item_color(row, item);
+                ctx.fill(rect, &Color::rgb8(r, g, b).with_alpha(0.2 + 0.8 * density));
+            }
+        }
     }
 
-    pub fn to_x(&self, t: Ns) -> f64 {
-        ((t as f64) - self.start) * self.scale
+    /// Draws a tick for each lone instant, and a bar from the first to the
+    /// last instant with a count badge where several share a bucket.
+    fn paint_instant_track(ctx: &mut PaintCtx, env: &Env, row: &Row, items: &[DrawItem], size: Size) {
+        let [r, g, b] = row.color.unwrap_or(view::INSTANT_COLOR);
+        let tick_color = Color::rgb8(r, g, b);
+        let badge_width = 30.0;
+        let mut next_badge_x = f64::NEG_INFINITY;
+        for item in items {
+            if let DrawItem::Instants { x0, x1, count } = *item {
+                ctx.fill(Rect::new(x0, 0.0, x1, size.height), &tick_color);
+                if count > 1 && x0 >= next_badge_x {
+                    Self::paint_text(ctx, env, &count.to_string(), (x1 + 2.0, size.height - 14.0), tick_color.clone());
+                    next_badge_x = x1 + badge_width;
+                }
+            }
+        }
     }
 
-    pub fn to_ns(&self, x: f64) -> f64 {
-        self.start + (x / self.scale)
+    fn paint_label(ctx: &mut PaintCtx, env: &Env, label: &str) {
+        Self::paint_text(ctx, env, label, (4.0, 2.0), Color::rgb8(0xFF, 0x00, 0x00));
     }
-}
 
-struct ViewQuant {
-    pub time_step: Ns,
-}
+    fn paint_text(ctx: &mut PaintCtx, env: &Env, text: &str, pos: (f64, f64), color: Color) {
+        // Text is easy; in real use TextLayout should be stored in the widget
+        // and reused.
+        let mut layout = TextLayout::new(text);
+        layout.set_font(FontDescriptor::new(FontFamily::SYSTEM_UI).with_size(12.0));
+        layout.set_text_color(color);
+        layout.rebuild_if_needed(ctx.text(), env);
+        layout.draw(ctx, pos);
+    }
 
-impl ViewQuant {
-    pub fn new(r: &Range<Ns>, width: f64) -> Self {
-        let ns_per_px = (r.end-r.start)/(width as u64);
-        let min_event_px = 2;
-        let step = ns_per_px * min_event_px;
-        let step = 1 << (64-step.leading_zeros());
-        Self { time_step: u64::max(1, step) }
+    /// The view after pressing `key`, WASD or the arrow keys to pan and
+    /// zoom, and 0 or Home to go back to the whole trace.
+    fn key_view(&self, key: &KbKey, width: f64, trace: &Trace) -> Option<Range<Ns>> {
+        let view = &self.view_range;
+        let at_x = self.mouse_x.unwrap_or(width / 2.0);
+        let key = match key {
+            KbKey::Character(c) => c.to_lowercase(),
+            KbKey::ArrowUp => "w".to_string(),
+            KbKey::ArrowLeft => "a".to_string(),
+            KbKey::ArrowDown => "s".to_string(),
+            KbKey::ArrowRight => "d".to_string(),
+            KbKey::Home => "0".to_string(),
+            _ => return None,
+        };
+        Some(match key.as_str() {
+            "w" => view::zoom_view(view, width, KEY_ZOOM, at_x),
+            "s" => view::zoom_view(view, width, 1.0 / KEY_ZOOM, at_x),
+            "a" => view::pan_view(view, width, width * KEY_PAN),
+            "d" => view::pan_view(view, width, -width * KEY_PAN),
+            "0" => trace.time_bounds()?,
+            _ => return None,
+        })
     }
 
-    pub fn round_down(&self, x: Ns) -> Ns {
-        x - (x % self.time_step)
+    /// The event on the track under `pos`, which may be one the view only
+    /// drew a bucket for.
+    fn hit_test(&self, trace: &Trace, pos: Point, width: f64) -> Option<(usize, HitEvent)> {
+        let y = pos.y - RULER_HEIGHT;
+        let row = view::rows(trace).into_iter().find(|row| row.y <= y && y < row.y + row.height)?;
+        let track = match row.track {
+            RowTrack::Track(i) => i,
+            RowTrack::Instant(_) => return None,
+        };
+        let map = ViewMap::new(&self.view_range, width);
+        if map.scale <= 0.0 {
+            return None;
+        }
+        let t = map.to_ns(pos.x).max(0.0) as Ns;
+        let slop = (HIT_SLOP_PX / map.scale) as Ns;
+        Some((track, trace.tracks[track].event_at(&trace.pool, t, slop)?))
     }
 
-    pub fn quantize(&self, r: &Range<Ns>) -> Range<Ns> {
-        self.round_down(r.start)..(self.round_down(r.end)+self.time_step)
+    /// Outlines an event in its row.
+    fn paint_highlight(ctx: &mut PaintCtx, map: &ViewMap, hit: &HitEvent, size: Size, color: &Color) {
+        let ts = hit.event.ts.unpack();
+        let x0 = map.to_x(ts);
+        let x1 = map.to_x(ts + hit.event.dur.unpack()).max(x0 + 1.0);
+        ctx.stroke(Rect::new(x0, 1.0, x1, size.height - 1.0), color, 2.0);
     }
-}
 
-struct TimelineWidget {
-    view_range: Range<Ns>,
-}
+    /// The kind, times and args of the selected event, in a panel on the
+    /// right. Times are from the start of the trace.
+    fn paint_details(ctx: &mut PaintCtx, env: &Env, trace: &Trace, (track, hit): &(usize, HitEvent), size: Size) {
+        let info = &trace.tracks[*track];
+        let ts = hit.event.ts.unpack();
+        let trace_start = trace.time_bounds().map_or(0, |b| b.start);
+        let mut lines = vec![
+            trace.kinds.display(hit.event.kind).into_owned(),
+            format!("Track: {}", info.meta.name),
+            format!("Start: {}", view::format_time(ts - trace_start)),
+            format!("Duration: {}", view::format_time(hit.event.dur.unpack())),
+        ];
+        for (key, value) in info.args.get(hit.index) {
+            match value {
+                Json::Str(s) => lines.push(format!("{}: {}", key, s)),
+                _ => lines.push(format!("{}: {}", key, value)),
+            }
+        }
 
-impl TimelineWidget {
-    fn paint_track(&self, ctx: &mut PaintCtx, trace: &Trace, env: &Env, track: &TrackInfo, size: Size) {
-        // let rect = Rect::from_origin_size(Point::ORIGIN, size);
-        // let fill_color = Color::rgb8(0x77, 0x00, 0x00);
-        // ctx.fill(rect, &fill_color);
-
-        let view = ViewMap::new(&self.view_range, size.width);
-        let quant = ViewQuant::new(&self.view_range, size.width);
-        let visible_events = gigatrace::aggregate_by_steps(&trace.pool, &track.track.block_locs, &track.zoom_index, quant.quantize(&self.view_range), quant.time_step);
-        // for ev in track.track.events(&trace.pool) {
-        for ev in visible_events.iter().filter_map(|x| x.0) {
-            let ts = ev.ts.unpack();
-            let dur = ev.dur.unpack();
-            let (start, end) = if dur > quant.time_step {
-                (ts, ts+dur)
-            } else {
-                let start = quant.round_down(ts);
-                (start, start+quant.time_step)
-            };
-            // println!("{:?}: {} - {} -> {:.1} - {:.1} / {}", self.view_range, start, end, view.to_x(start), view.to_x(end), size.width);
-            let rect = Rect::new(view.to_x(start),0.0,view.to_x(end),size.height);
-            let fill_color = Color::rgb8(0x00, 0x00, (ev.kind % 250) as u8);
-            ctx.fill(rect, &fill_color);
+        let x0 = (size.width - PANEL_WIDTH).max(0.0);
+        let panel = Rect::new(x0, RULER_HEIGHT, size.width, RULER_HEIGHT + LINE_HEIGHT * lines.len() as f64 + 16.0);
+        ctx.fill(panel, &Color::WHITE.with_alpha(0.95));
+        ctx.stroke(panel, &Color::grey8(0x80), 1.0);
+        for (i, line) in lines.iter().enumerate() {
+            Self::paint_text(ctx, env, line, (x0 + 8.0, RULER_HEIGHT + 8.0 + LINE_HEIGHT * i as f64), Color::BLACK);
         }
     }
 
-    fn zoom(&mut self, zoom_factor: f64, at_x: f64, size: Size) -> bool {
-        let delta_time = self.view_range.end - self.view_range.start;
-        let new_delta_time = (delta_time as f64) * zoom_factor; // TODO max zoom
-        let view = ViewMap::new(&self.view_range, size.width);
-        let zoom_time = view.to_ns(at_x);
-        let r = at_x / size.width;
-        let new_start = zoom_time - new_delta_time * r;
-        let new_end = new_start + new_delta_time;
-        self.view_range = (new_start as u64)..(new_end as u64);
-        true
+    /// Times from the start of the trace along the top, with faint lines
+    /// down through the tracks at each tick.
+    fn paint_ruler(ctx: &mut PaintCtx, env: &Env, trace: &Trace, view_range: &Range<Ns>, size: Size) {
+        let origin = trace.time_bounds().map_or(0, |b| b.start);
+        ctx.fill(Rect::new(0.0, 0.0, size.width, RULER_HEIGHT), &Color::grey8(0xEE));
+        for tick in view::ruler_ticks(view_range, size.width, origin, MIN_TICK_PX) {
+            ctx.fill(Rect::new(tick.x, RULER_HEIGHT, tick.x + 1.0, size.height), &Color::grey8(0xE0));
+            ctx.fill(Rect::new(tick.x, RULER_HEIGHT - 6.0, tick.x + 1.0, RULER_HEIGHT), &Color::grey8(0x40));
+            Self::paint_text(ctx, env, &tick.label, (tick.x + 3.0, 4.0), Color::grey8(0x40));
+        }
     }
 
     fn zoom_ratio(delta: f64) -> f64 {
@@ -105,25 +190,78 @@ impl TimelineWidget {
 }
 
 impl Widget<Arc<Trace>> for TimelineWidget {
-    fn event(&mut self, ctx: &mut EventCtx, event: &Event, _data: &mut Arc<Trace>, _env: &Env) {
-        if !ctx.is_handled() {
-            if let Event::Wheel(mouse) = event {
+    fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut Arc<Trace>, _env: &Env) {
+        if ctx.is_handled() {
+            return;
+        }
+        let width = ctx.size().width;
+        let new_view = match event {
+            Event::WindowConnected => {
+                ctx.request_focus();
+                None
+            }
+            Event::Wheel(mouse) => {
                 let factor = Self::zoom_ratio(mouse.wheel_delta.y);
-                if self.zoom(factor, mouse.pos.x, ctx.size()) {
+                Some(view::zoom_view(&self.view_range, width, factor, mouse.pos.x))
+            }
+            Event::MouseDown(mouse) => {
+                ctx.set_active(true);
+                ctx.request_focus();
+                self.drag_x = Some(mouse.pos.x);
+                self.press_pos = Some(mouse.pos);
+                ctx.set_handled();
+                None
+            }
+            Event::MouseMove(mouse) => {
+                self.mouse_x = Some(mouse.pos.x);
+                if !ctx.is_active() {
+                    let hovered = self.hit_test(data, mouse.pos, width);
+                    let key = |h: &Option<(usize, HitEvent)>| h.as_ref().map(|(track, hit)| (*track, hit.index));
+                    if key(&hovered) != key(&self.hovered) {
+                        self.hovered = hovered;
+                        ctx.request_paint();
+                    }
+                }
+                let last_x = if ctx.is_active() { self.drag_x.replace(mouse.pos.x) } else { None };
+                last_x.map(|last_x| view::pan_view(&self.view_range, width, mouse.pos.x - last_x))
+            }
+            Event::MouseUp(mouse) => {
+                ctx.set_active(false);
+                self.drag_x = None;
+                if self.press_pos.take().map_or(false, |p| p.distance(mouse.pos) <= CLICK_SLOP_PX) {
+                    // Clicking on nothing clears the selection
+                    self.selected = self.hit_test(data, mouse.pos, width);
                     ctx.request_paint();
-                    ctx.set_handled();
                 }
+                None
             }
+            Event::KeyDown(key) => self.key_view(&key.key, width, data),
+            Event::Command(cmd) if cmd.is(QUERY_DONE) => {
+                if self.worker.poll() {
+                    ctx.request_paint();
+                }
+                ctx.set_handled();
+                None
+            }
+            _ => None,
+        };
+        if let Some(view) = new_view {
+            self.view_range = view;
+            ctx.request_paint();
+            ctx.set_handled();
         }
     }
 
     fn lifecycle(
         &mut self,
-        _ctx: &mut LifeCycleCtx,
-        _event: &LifeCycle,
+        ctx: &mut LifeCycleCtx,
+        event: &LifeCycle,
         _data: &Arc<Trace>,
         _env: &Env,
     ) {
+        if let LifeCycle::WidgetAdded = event {
+            ctx.register_for_focus();
+        }
     }
 
     fn update(&mut self, _ctx: &mut UpdateCtx, _old_data: &Arc<Trace>, _data: &Arc<Trace>, _env: &Env) {}
@@ -145,37 +283,94 @@ impl Widget<Arc<Trace>> for TimelineWidget {
         ctx.fill(rect, &Color::WHITE);
 
         let trace = data.deref();
-        let track_height = 30.0;
-        ctx.with_save(|ctx| {
-            for track in &data.tracks {
-                self.paint_track(ctx, trace, env, track, Size::new(size.width, track_height));
-                ctx.transform(Affine::translate((0.0, track_height)));
-            }
-        });
+        Self::paint_ruler(ctx, env, trace, &self.view_range, size);
+        let rows = view::rows(trace).into_iter().take_while(|row| row.y < size.height - RULER_HEIGHT).collect::<Vec<_>>();
+        let tracks = rows.iter().map(|row| row.track).collect::<Vec<_>>();
+        self.worker.request(self.view_range.clone(), size.width, tracks);
+        self.worker.poll();
+        // Until the workers catch up, draw the last result moved to where
+        // its times are in the current view
+        let latest = self.worker.latest();
+        let (scale, offset) = latest.map_or((1.0, 0.0), |l| l.transform_to(&self.view_range, size.width));
+        let map = ViewMap::new(&self.view_range, size.width);
+        let highlights = [(&self.hovered, Color::grey8(0x60)), (&self.selected, Color::rgb8(0xFF, 0x80, 0x00))];
 
-        // Text is easy; in real use TextLayout should be stored in the widget
-        // and reused.
-        let text_color = Color::rgb8(0xFF, 0x00, 0x00);
-        let mut layout = TextLayout::new("Gigatrace!");
-        layout.set_font(FontDescriptor::new(FontFamily::SYSTEM_UI).with_size(12.0));
-        layout.set_text_color(text_color);
-        layout.rebuild_if_needed(ctx.text(), env);
-        // layout.draw(ctx, (10.0, 10.0));
+        for row in &rows {
+            // Rows not in the last result are left blank until they're queried
+            let mut items = latest.and_then(|l| l.items_for(row.track)).unwrap_or(&[]).to_vec();
+            if (scale, offset) != (1.0, 0.0) {
+                for item in &mut items {
+                    match item {
+                        DrawItem::Span { x0, x1, .. } | DrawItem::Instants { x0, x1, .. } => {
+                            *x0 = *x0 * scale + offset;
+                            *x1 = *x1 * scale + offset;
+                        }
+                    }
+                }
+            }
+            let items = &items[..];
+            ctx.with_save(|ctx| {
+                ctx.transform(Affine::translate((0.0, RULER_HEIGHT + row.y)));
+                let row_size = Size::new(size.width, row.height);
+                match row.track {
+                    RowTrack::Track(_) => Self::paint_track(ctx, row, items, row_size),
+                    RowTrack::Instant(_) => Self::paint_instant_track(ctx, env, row, items, row_size),
+                }
+                for (hit, color) in &highlights {
+                    if let Some((track, hit)) = hit {
+                        if row.track == RowTrack::Track(*track) {
+                            Self::paint_highlight(ctx, &map, hit, row_size, color);
+                        }
+                    }
+                }
+                if let Some(label) = &row.label {
+                    Self::paint_label(ctx, env, label);
+                }
+            });
+        }
+        if let Some(selected) = &self.selected {
+            Self::paint_details(ctx, env, trace, selected, size);
+        }
     }
 }
 
 pub fn main() {
     // let trace = Trace::demo_trace(5, 200_000_000);
-    let trace = Trace::demo_trace(5, 2_000_000);
+    let mut trace = Trace::demo_trace(5, 2_000_000);
+    let bounds = trace.time_bounds().unwrap_or(0..1000);
+    let vsync_kind = trace.kinds.intern("vsync");
+    let vsyncs = (bounds.start..bounds.end).step_by(16_666_667).map(|ts| InstantEvent::new(vsync_kind, ts).unwrap());
+    let vsync_meta = TrackMeta { name: "vsync".to_string(), ..TrackMeta::default() };
+    trace.add_instant_track(vsync_meta, vsyncs).expect("vsyncs are sorted");
+    let trace = Arc::new(trace);
+
+    // The workers start before the app, so they get the handle for waking
+    // it up once it exists
+    let sink: Arc<Mutex<Option<ExtEventSink>>> = Arc::default();
+    let worker_sink = sink.clone();
+    let worker = QueryWorker::new(trace.clone(), MAX_QUERY_THREADS, move || {
+        if let Some(sink) = &*worker_sink.lock().unwrap() {
+            // Only fails if the app has shut down
+            let _ = sink.submit_command(QUERY_DONE, (), Target::Auto);
+        }
+    });
     let timeline = TimelineWidget {
-        view_range: trace.time_bounds().unwrap_or(0..1000)
+        view_range: trace.time_bounds().unwrap_or(0..1000),
+        worker,
+        mouse_x: None,
+        drag_x: None,
+        press_pos: None,
+        hovered: None,
+        selected: None,
     };
 
     let window = WindowDesc::new(move || timeline).title(
         LocalizedString::new("gigatrace-window-title").with_placeholder("Gigatrace"),
     );
-    AppLauncher::with_window(window)
+    let launcher = AppLauncher::with_window(window);
+    *sink.lock().unwrap() = Some(launcher.get_external_handle());
+    launcher
         .use_simple_logger()
-        .launch(Arc::new(trace))
+        .launch(trace)
         .expect("launch failed");
 }